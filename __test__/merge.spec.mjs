@@ -2,8 +2,22 @@ import test from "ava";
 import os from "os";
 import fs from "fs/promises";
 import path from "path";
+import { createReadStream } from "fs";
+import { Readable } from "stream";
 
-import { DeduplicateStrategy, MergeStrategy, merge } from "../index.js";
+import {
+  AggregationFunction,
+  ConflictPolicy,
+  CrossJoinLimitAction,
+  DeduplicateStrategy,
+  EmptyKeyPolicy,
+  KeyNormalization,
+  MergeStrategy,
+  RowFormat,
+  TieBreak,
+  merge,
+  mergeRows,
+} from "../index.js";
 
 async function getTempFilePath() {
   const tempDir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
@@ -36,8 +50,13 @@ test(`test output header callback`, async (t) => {
   t.snapshot(await fs.readFile(output, { encoding: "ascii" }));
 });
 
+// Strategies added later are covered by explicit assertions below.
+const snapshotStrategies = Object.keys(DeduplicateStrategy).filter(
+  (strategy) => !["KeepLast", "Custom", "Distinct"].includes(strategy)
+);
+
 for (let mergeStrategy in MergeStrategy) {
-  for (let deduplicateStrategy in DeduplicateStrategy) {
+  for (let deduplicateStrategy of snapshotStrategies) {
     test(`test strategy: ${mergeStrategy} - ${deduplicateStrategy}`, async (t) => {
       const output = await getTempFilePath();
       await t.notThrowsAsync(
@@ -59,3 +78,946 @@ for (let mergeStrategy in MergeStrategy) {
     });
   }
 }
+
+test(`test key normalization`, async (t) => {
+  const output = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/normalize-left.csv",
+    "./__test__/fixtures/normalize-right.csv",
+    {
+      mergeStrategy: MergeStrategy.And,
+      deduplicateStrategy: DeduplicateStrategy.KeepAll,
+      leftKey: "key",
+      rightKey: "key",
+      keyNormalization: [KeyNormalization.Trim, KeyNormalization.StripLeadingZeros],
+      output,
+    }
+  );
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tname\tvalue\n001\tAlice\t\n1\t\tx\n 2\tBob\t\n02 \t\ty\n"
+  );
+  await fs.rm(output);
+});
+
+test(`test key normalization rejects input not sorted by normalized key`, async (t) => {
+  const dir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
+  const left = path.join(dir, "left.csv");
+  const right = path.join(dir, "right.csv");
+  // Sorted by raw key, but "b" sorts before "c" once lowercased.
+  await fs.writeFile(left, "key\tname\nB\tBob\nc\tCarol\nd\tDan\n");
+  await fs.writeFile(right, "key\tvalue\na\tx\nC\ty\nb\tz\n");
+  await t.throwsAsync(
+    merge(left, right, {
+      mergeStrategy: MergeStrategy.And,
+      deduplicateStrategy: DeduplicateStrategy.KeepAll,
+      leftKey: "key",
+      rightKey: "key",
+      keyNormalization: [KeyNormalization.Lowercase],
+      output: path.join(dir, "output.txt"),
+    }),
+    { message: /^Right input is not sorted by key at line 3/ }
+  );
+  await fs.rm(dir, { recursive: true });
+});
+
+test(`test distinct union of unsorted input`, async (t) => {
+  const dir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
+  const left = path.join(dir, "left.csv");
+  const right = path.join(dir, "right.csv");
+  const output = path.join(dir, "output.txt");
+  await fs.writeFile(left, "key\tname\n3\tCid\n1\tAnn\n3\tCid\n");
+  await fs.writeFile(right, "key\tname\n2\tBob\n1\tAnn\n1\tAnna\n");
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Distinct,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+    output,
+  };
+
+  for (let groupMemoryLimit of [undefined, 1]) {
+    await merge(left, right, { ...options, groupMemoryLimit });
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      "key\tname\n2\tBob\n1\tAnn\n1\tAnna\n3\tCid\n"
+    );
+  }
+
+  // `And` matches rows by key, so it still needs sorted input.
+  await t.throwsAsync(merge(left, right, { ...options, mergeStrategy: MergeStrategy.And }), {
+    message: /^Right input is not sorted by key at line 2/,
+  });
+  await fs.rm(dir, { recursive: true });
+});
+
+test(`test empty key policy: NeverMatch`, async (t) => {
+  const output = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/empty-keys-left.csv",
+    "./__test__/fixtures/empty-keys-right.csv",
+    {
+      mergeStrategy: MergeStrategy.Or,
+      deduplicateStrategy: DeduplicateStrategy.Reduce,
+      leftKey: "key",
+      rightKey: "key",
+      isNumberKey: true,
+      emptyKeyPolicy: EmptyKeyPolicy.NeverMatch,
+      nullTokens: ["NULL"],
+      output,
+    }
+  );
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tname\tvalue\n\tnokey\t\nNULL\tnull-key\t\n\t\tnokey\n1\tA\tX\n2\tB\t\n3\t\tY\n"
+  );
+  await fs.rm(output);
+});
+
+test(`test empty key policy: Reject`, async (t) => {
+  const output = await getTempFilePath();
+  const rejects = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/empty-keys-left.csv",
+    "./__test__/fixtures/empty-keys-right.csv",
+    {
+      mergeStrategy: MergeStrategy.Or,
+      deduplicateStrategy: DeduplicateStrategy.KeepAll,
+      leftKey: "key",
+      rightKey: "key",
+      isNumberKey: true,
+      emptyKeyPolicy: EmptyKeyPolicy.Reject,
+      emptyKeyRejectOutput: rejects,
+      nullTokens: ["NULL"],
+      output,
+    }
+  );
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tname\tvalue\n1\tA\t\n1\t\tX\n2\tB\t\n3\t\tY\n"
+  );
+  t.is(
+    await fs.readFile(rejects, { encoding: "ascii" }),
+    "key\tname\tvalue\n\tnokey\t\nNULL\tnull-key\t\n\t\tnokey\n"
+  );
+  await fs.rm(output);
+  await fs.rm(rejects);
+});
+
+for (const [mergeStrategy, emptyKeyPolicy, expected, rejected] of [
+  [
+    "Or",
+    "MatchEachOther",
+    "1\tA\t\n1\t\tX\n2\tB\t\n3\t\tY\nNULL\tnull-middle\t\nNULL\tnull-end\t\nNULL\t\tnull-end\n",
+  ],
+  [
+    "And",
+    "MatchEachOther",
+    "1\tA\t\n1\t\tX\nNULL\tnull-middle\t\nNULL\tnull-end\t\nNULL\t\tnull-end\n",
+  ],
+  ["AndNot", "MatchEachOther", "2\tB\t\n"],
+  [
+    "Or",
+    "NeverMatch",
+    "1\tA\t\n1\t\tX\nNULL\tnull-middle\t\n2\tB\t\nNULL\tnull-end\t\n3\t\tY\nNULL\t\tnull-end\n",
+  ],
+  ["Or", "Drop", "1\tA\t\n1\t\tX\n2\tB\t\n3\t\tY\n"],
+  [
+    "And",
+    "Reject",
+    "1\tA\t\n1\t\tX\n",
+    "NULL\tnull-middle\t\nNULL\tnull-end\t\nNULL\t\tnull-end\n",
+  ],
+]) {
+  test(`test null keys inside sorted input: ${mergeStrategy} - ${emptyKeyPolicy}`, async (t) => {
+    const output = await getTempFilePath();
+    const rejects = await getTempFilePath();
+    await merge(
+      "./__test__/fixtures/null-keys-left.csv",
+      "./__test__/fixtures/null-keys-right.csv",
+      {
+        mergeStrategy: MergeStrategy[mergeStrategy],
+        deduplicateStrategy: DeduplicateStrategy.KeepAll,
+        leftKey: "key",
+        rightKey: "key",
+        isNumberKey: true,
+        emptyKeyPolicy: EmptyKeyPolicy[emptyKeyPolicy],
+        emptyKeyRejectOutput: rejects,
+        nullTokens: ["NULL"],
+        output,
+      }
+    );
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      "key\tname\tvalue\n" + expected
+    );
+    if (rejected) {
+      t.is(
+        await fs.readFile(rejects, { encoding: "ascii" }),
+        "key\tname\tvalue\n" + rejected
+      );
+      await fs.rm(rejects);
+    }
+    await fs.rm(output);
+  });
+}
+
+test(`test null tokens match normalized keys`, async (t) => {
+  const dir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
+  const left = path.join(dir, "left.csv");
+  const right = path.join(dir, "right.csv");
+  const output = path.join(dir, "output.txt");
+  await fs.writeFile(left, "key\tname\n NULL \tpadded\n1\tA\n");
+  await fs.writeFile(right, "key\tvalue\nnull\tlower\n1\tX\n");
+  await merge(left, right, {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.KeepAll,
+    leftKey: "key",
+    rightKey: "key",
+    keyNormalization: [KeyNormalization.Trim, KeyNormalization.Lowercase],
+    emptyKeyPolicy: EmptyKeyPolicy.Drop,
+    nullTokens: ["NULL"],
+    output,
+  });
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tname\tvalue\n1\tA\t\n1\t\tX\n"
+  );
+  await fs.rm(dir, { recursive: true });
+});
+
+test(`test provenance columns`, async (t) => {
+  const output = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    {
+      mergeStrategy: MergeStrategy.And,
+      deduplicateStrategy: DeduplicateStrategy.Reduce,
+      leftKey: "key",
+      rightKey: "key",
+      isNumberKey: true,
+      appendLineNumber: true,
+      appendSourcePath: true,
+      output,
+    }
+  );
+  const lines = (await fs.readFile(output, { encoding: "ascii" }))
+    .trim()
+    .split("\n")
+    .map((line) => line.split("\t").slice(5));
+  t.deepEqual(lines, [
+    ["__left_line", "__right_line", "__left_source", "__right_source"],
+    ...[
+      ["5", "3"],
+      ["7", "6"],
+      ["9", "8"],
+      ["16", "12"],
+    ].map((pair) => [
+      ...pair,
+      "./__test__/fixtures/list1-sorted.csv",
+      "./__test__/fixtures/list2-sorted.csv",
+    ]),
+  ]);
+  await fs.rm(output);
+});
+
+for (let deduplicateStrategy of ["KeepAll", "KeepFirst", "Reduce"]) {
+  for (let tieBreak in TieBreak) {
+    test(`test tie break: ${deduplicateStrategy} - ${tieBreak}`, async (t) => {
+      const options = {
+        mergeStrategy: MergeStrategy.Or,
+        deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+        leftKey: "key",
+        rightKey: "key",
+        isNumberKey: true,
+        tieBreak: TieBreak[tieBreak],
+      };
+      const output = await getTempFilePath();
+      await merge(
+        "./__test__/fixtures/list1-sorted.csv",
+        "./__test__/fixtures/list2-sorted.csv",
+        { ...options, output }
+      );
+      const result = await fs.readFile(output, { encoding: "ascii" });
+      t.snapshot(result);
+
+      // Rows held back for the tie break spill without changing the order.
+      await merge(
+        "./__test__/fixtures/list1-sorted.csv",
+        "./__test__/fixtures/list2-sorted.csv",
+        { ...options, groupMemoryLimit: 1, output }
+      );
+      t.is(await fs.readFile(output, { encoding: "ascii" }), result);
+      await fs.rm(output);
+    });
+  }
+}
+
+for (let [tieBreak, key] of [
+  ["LeftFirst", "1"],
+  ["RightFirst", "001"],
+]) {
+  test(`test tie break decides Reduce winner: ${tieBreak}`, async (t) => {
+    const output = await getTempFilePath();
+    await merge(
+      "./__test__/fixtures/normalize-left.csv",
+      "./__test__/fixtures/normalize-right.csv",
+      {
+        mergeStrategy: MergeStrategy.And,
+        deduplicateStrategy: DeduplicateStrategy.Reduce,
+        leftKey: "key",
+        rightKey: "key",
+        keyNormalization: [KeyNormalization.Trim, KeyNormalization.StripLeadingZeros],
+        tieBreak: TieBreak[tieBreak],
+        output,
+      }
+    );
+    const lines = (await fs.readFile(output, { encoding: "ascii" })).split("\n");
+    t.is(lines[1], `${key}\tAlice\tx`);
+    await fs.rm(output);
+  });
+}
+
+test(`test merge from buffer and stream inputs`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Reduce,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+  };
+  const expectedOutput = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    { ...options, output: expectedOutput }
+  );
+
+  const output = await getTempFilePath();
+  await merge(
+    await fs.readFile("./__test__/fixtures/list1-sorted.csv"),
+    createReadStream("./__test__/fixtures/list2-sorted.csv", {
+      highWaterMark: 16,
+    }),
+    { ...options, output }
+  );
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    await fs.readFile(expectedOutput, { encoding: "ascii" })
+  );
+
+  const failing = new Readable({ read() {} });
+  failing.push("key\tfeature_right\n1\t100\n");
+  setTimeout(() => failing.destroy(new Error("upload aborted")), 10);
+  await t.throwsAsync(
+    merge("./__test__/fixtures/list1-sorted.csv", failing, { ...options, output }),
+    { message: "Error: upload aborted" }
+  );
+
+  await fs.rm(output);
+  await fs.rm(expectedOutput);
+});
+
+test(`test stream input is paused while the merge falls behind`, async (t) => {
+  const output = await getTempFilePath();
+  const rows = Array.from({ length: 100 }, (_, i) => `${i + 1}\tL${i + 1}\n`);
+  const left = Readable.from(["key\tleft\n", ...rows]);
+  // The merge waits for the right header, so left chunks pile up meanwhile.
+  const right = new Readable({ read() {} });
+  let paused = false;
+  left.on("pause", () => {
+    if (!paused) {
+      paused = true;
+      right.push("key\tright\n50\tR50\n");
+      right.push(null);
+    }
+  });
+  await merge(left, right, {
+    mergeStrategy: MergeStrategy.And,
+    deduplicateStrategy: DeduplicateStrategy.KeepAll,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+    output,
+  });
+  t.true(paused);
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tleft\tright\n50\tL50\t\n50\t\tR50\n"
+  );
+  await fs.rm(output);
+});
+
+test(`test failed merge setup unsubscribes stream input`, async (t) => {
+  const left = new Readable({ read() {} });
+  t.throws(
+    () =>
+      merge(left, {}, {
+        mergeStrategy: MergeStrategy.Or,
+        deduplicateStrategy: DeduplicateStrategy.KeepAll,
+        leftKey: "key",
+        rightKey: "key",
+        output: "unused",
+      })
+  );
+  t.is(left.listenerCount("data"), 0);
+  t.is(left.listenerCount("end"), 0);
+  t.is(left.listenerCount("error"), 0);
+});
+
+test(`test concurrent merges of file streams`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Reduce,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+  };
+  const expectedOutput = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    { ...options, output: expectedOutput }
+  );
+  const expected = await fs.readFile(expectedOutput, { encoding: "ascii" });
+  const stream = (name) =>
+    createReadStream(`./__test__/fixtures/${name}`, { highWaterMark: 16 });
+
+  // More merges than libuv pool threads, which the fs streams read on.
+  const outputs = await Promise.all(
+    Array.from({ length: 8 }, async () => {
+      const output = await getTempFilePath();
+      await merge(stream("list1-sorted.csv"), stream("list2-sorted.csv"), {
+        ...options,
+        output,
+      });
+      return output;
+    })
+  );
+  for (const output of outputs) {
+    t.is(await fs.readFile(output, { encoding: "ascii" }), expected);
+    await fs.rm(output);
+  }
+
+  const rowCounts = await Promise.all(
+    Array.from({ length: 8 }, async () => {
+      let count = 0;
+      for await (const batch of mergeRows(
+        stream("list1-sorted.csv"),
+        stream("list2-sorted.csv"),
+        { ...options, batchSize: 2 }
+      )) {
+        count += batch.length;
+      }
+      return count;
+    })
+  );
+  t.deepEqual(
+    rowCounts,
+    Array(8).fill(expected.trim().split("\n").length - 1)
+  );
+
+  await fs.rm(expectedOutput);
+});
+
+test(`test merge rows as async iterator`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Reduce,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+  };
+  const output = await getTempFilePath();
+  await merge(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    { ...options, output }
+  );
+  const [header, ...expected] = (await fs.readFile(output, { encoding: "ascii" }))
+    .trim()
+    .split("\n")
+    .map((line) => line.split("\t"));
+
+  const batches = [];
+  for await (const batch of mergeRows(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    { ...options, batchSize: 5, rowFormat: RowFormat.Array }
+  )) {
+    batches.push(batch);
+  }
+  t.deepEqual(
+    batches.map((batch) => batch.length),
+    [5, 5, 5, 1]
+  );
+  t.deepEqual(batches.flat(), expected);
+
+  for await (const [row] of mergeRows(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    { ...options, batchSize: 1 }
+  )) {
+    t.deepEqual(row, Object.fromEntries(header.map((h, i) => [h, expected[0][i]])));
+    break;
+  }
+
+  await fs.rm(output);
+});
+
+test(`test reduce aggregations`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Reduce,
+    leftKey: "key",
+    rightKey: "key",
+    aggregations: [
+      { column: "amount", function: AggregationFunction.Sum },
+      { column: "price", function: AggregationFunction.Mean },
+      { column: "tag", function: AggregationFunction.Concat, separator: "," },
+      { column: "city", function: AggregationFunction.CountDistinct },
+    ],
+  };
+  const output = await getTempFilePath();
+  await merge(
+    Buffer.from("key\tamount\ttag\n1\t10\ta\n1\t2.5\tb\n2\t7\tc\n"),
+    Buffer.from("key\tprice\tcity\n1\t3\tRiga\n1\t4\tRiga\n3\t\tOslo\n"),
+    { ...options, output }
+  );
+  t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+    "key\tamount\ttag\tprice\tcity",
+    "1\t12.5\ta,b\t3.5\t1",
+    "2\t7\tc\t\t0",
+    "3\t0\t\t\t1",
+    "",
+  ]);
+
+  await t.throwsAsync(
+    merge(
+      Buffer.from("key\tamount\n1\t10\n1\tn/a\n"),
+      Buffer.from("key\tprice\n"),
+      { ...options, aggregations: [options.aggregations[0]], output }
+    ),
+    { message: 'Cannot aggregate column amount: "n/a" is not a number' }
+  );
+  await t.throwsAsync(
+    merge(Buffer.from("key\n"), Buffer.from("key\n"), { ...options, output }),
+    { message: "Unknown aggregation column: amount" }
+  );
+
+  await fs.rm(output);
+});
+
+test(`test keep last`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    leftKey: "key",
+    rightKey: "key",
+  };
+  const left = Buffer.from("key\tname\n1\told\n1\tnew\n2\tonly\n");
+  const right = Buffer.from("key\tname\n1\tnewest\n");
+
+  for (let [deduplicateStrategy, expected] of [
+    ["KeepFirst", ["1\told", "2\tonly"]],
+    ["KeepLast", ["1\tnewest", "2\tonly"]],
+  ]) {
+    const output = await getTempFilePath();
+    await merge(left, right, {
+      ...options,
+      deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+      tieBreak: TieBreak.LeftFirst,
+      output,
+    });
+    t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+      "key\tname",
+      ...expected,
+      "",
+    ]);
+    await fs.rm(output);
+  }
+});
+
+test(`test conflict policies`, async (t) => {
+  const left = Buffer.from("key\tname\tcity\n1\tAnn\tRiga\n2\tBob\tOslo\n");
+  const right = Buffer.from("key\tname\tcity\n1\tAnna\tRiga\n2\t\tBergen\n");
+  const options = {
+    mergeStrategy: MergeStrategy.And,
+    deduplicateStrategy: DeduplicateStrategy.Reduce,
+    leftKey: "key",
+    rightKey: "key",
+    tieBreak: TieBreak.LeftFirst,
+  };
+
+  const output = await getTempFilePath();
+  const report = await getTempFilePath();
+  await merge(left, right, {
+    ...options,
+    conflictPolicies: [
+      { column: "name", policy: ConflictPolicy.PreferLeft },
+      { column: "city", policy: ConflictPolicy.Report },
+    ],
+    conflictReportOutput: report,
+    output,
+  });
+  t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+    "key\tname\tcity",
+    "1\tAnn\tRiga",
+    "2\tBob\tBergen",
+    "",
+  ]);
+  t.deepEqual((await fs.readFile(report, { encoding: "ascii" })).split("\n"), [
+    "key\tcolumn\tleft_value\tright_value",
+    "2\tcity\tOslo\tBergen",
+    "",
+  ]);
+
+  await t.throwsAsync(
+    merge(left, right, {
+      ...options,
+      deduplicateStrategy: DeduplicateStrategy.CrossJoin,
+      conflictPolicies: [{ column: "name", policy: ConflictPolicy.Error }],
+      output,
+    }),
+    { message: 'Conflicting values for column name at key 1: "Ann" vs "Anna"' }
+  );
+  await t.throwsAsync(
+    merge(left, right, {
+      ...options,
+      conflictPolicies: [{ column: "city", policy: ConflictPolicy.Report }],
+      output,
+    }),
+    { message: "conflictReportOutput is required for ConflictPolicy.Report" }
+  );
+
+  await fs.rm(output);
+  await fs.rm(report);
+});
+
+test(`test cross join limits`, async (t) => {
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.CrossJoin,
+    leftKey: "key",
+    rightKey: "key",
+    isNumberKey: true,
+  };
+  const output = await getTempFilePath();
+
+  const stats = await merge(
+    "./__test__/fixtures/list1-sorted.csv",
+    "./__test__/fixtures/list2-sorted.csv",
+    {
+      ...options,
+      crossJoinLimits: { maxProduct: 6, action: CrossJoinLimitAction.Skip },
+      output,
+    }
+  );
+  t.deepEqual(stats, {
+    crossJoinLimitedKeys: ["2"],
+    warnings: ["Cross join limit exceeded for key 2: 12 combinations exceed maxProduct 6"],
+  });
+  const lines = (await fs.readFile(output, { encoding: "ascii" })).split("\n");
+  t.is(lines.filter((line) => line.startsWith("2\t")).length, 0);
+
+  await t.throwsAsync(
+    merge(
+      "./__test__/fixtures/list1-sorted.csv",
+      "./__test__/fixtures/list2-sorted.csv",
+      { ...options, crossJoinLimits: { maxGroupSize: 4 }, output }
+    ),
+    { message: "Cross join limit exceeded for key 2: more rows than maxGroupSize 4" }
+  );
+
+  await fs.rm(output);
+});
+
+test(`test spilled groups match in-memory output`, async (t) => {
+  for (let deduplicateStrategy of [
+    "RemoveSimilar",
+    "Reduce",
+    "CrossJoin",
+    "CrossJoinAndRemoveSimilar",
+  ]) {
+    const options = {
+      mergeStrategy: MergeStrategy.Or,
+      deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+      leftKey: "key",
+      rightKey: "key",
+      isNumberKey: true,
+    };
+    const expectedOutput = await getTempFilePath();
+    await merge(
+      "./__test__/fixtures/list1-sorted.csv",
+      "./__test__/fixtures/list2-sorted.csv",
+      { ...options, output: expectedOutput }
+    );
+    const output = await getTempFilePath();
+    await merge(
+      "./__test__/fixtures/list1-sorted.csv",
+      "./__test__/fixtures/list2-sorted.csv",
+      { ...options, groupMemoryLimit: 64, output }
+    );
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      await fs.readFile(expectedOutput, { encoding: "ascii" })
+    );
+    await fs.rm(output);
+    await fs.rm(expectedOutput);
+  }
+});
+
+test(`test spilling a skewed key with a tiny memory limit`, async (t) => {
+  const dir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
+  const left = path.join(dir, "left.csv");
+  const right = path.join(dir, "right.csv");
+  const rows = Array.from({ length: 5000 }, (_, i) => `1\t${(i * 7919) % 2500}\n`);
+  await fs.writeFile(left, "key\tvalue\n" + rows.join(""));
+  await fs.writeFile(right, "key\tother\n1\tx\n1\ty\n");
+  for (let deduplicateStrategy of ["RemoveSimilar", "Reduce", "CrossJoinAndRemoveSimilar"]) {
+    const options = {
+      mergeStrategy: MergeStrategy.Or,
+      deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+      leftKey: "key",
+      rightKey: "key",
+    };
+    const expectedOutput = path.join(dir, "expected.txt");
+    await merge(left, right, { ...options, output: expectedOutput });
+    const output = path.join(dir, "output.txt");
+    await merge(left, right, { ...options, groupMemoryLimit: 64, output });
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      await fs.readFile(expectedOutput, { encoding: "ascii" })
+    );
+  }
+  await fs.rm(dir, { recursive: true });
+});
+
+test(`test remove similar ignoring columns`, async (t) => {
+  const left = Buffer.from(
+    "key\tname\tloaded_at\n1\tAnn\t10:00\n1\tAnn\t11:00\n2\tBob\t10:00\n"
+  );
+  const right = Buffer.from("key\tcity\n1\tRiga\n1\tOslo\n");
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    leftKey: "key",
+    rightKey: "key",
+    appendLineNumber: true,
+  };
+
+  const output = await getTempFilePath();
+  await merge(left, right, {
+    ...options,
+    deduplicateStrategy: DeduplicateStrategy.RemoveSimilar,
+    similarityIgnoreColumns: ["loaded_at", "__left_line", "__right_line"],
+    output,
+  });
+  t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+    "key\tname\tloaded_at\tcity\t__left_line\t__right_line",
+    "1\t\t\tOslo\t\t2",
+    "1\t\t\tRiga\t\t1",
+    "1\tAnn\t10:00\t\t1\t",
+    "2\tBob\t10:00\t\t3\t",
+    "",
+  ]);
+
+  await merge(left, right, {
+    ...options,
+    deduplicateStrategy: DeduplicateStrategy.CrossJoinAndRemoveSimilar,
+    similarityColumns: ["key", "name", "city"],
+    output,
+  });
+  t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+    "key\tname\tloaded_at\tcity\t__left_line\t__right_line",
+    "1\tAnn\t10:00\tOslo\t1\t2",
+    "1\tAnn\t10:00\tRiga\t1\t1",
+    "2\tBob\t10:00\t\t3\t",
+    "",
+  ]);
+
+  await t.throwsAsync(
+    merge(left, right, {
+      ...options,
+      deduplicateStrategy: DeduplicateStrategy.RemoveSimilar,
+      similarityColumns: ["key"],
+      similarityIgnoreColumns: ["loaded_at"],
+      output,
+    }),
+    { message: "similarityColumns and similarityIgnoreColumns are exclusive" }
+  );
+
+  await fs.rm(output);
+});
+
+test(`test audit output`, async (t) => {
+  const left = Buffer.from("key\tname\n1\tAnn\n1\tAnn\n2\tBob\n");
+  const right = Buffer.from("key\tname\n1\tAnna\n");
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    leftKey: "key",
+    rightKey: "key",
+    tieBreak: TieBreak.LeftFirst,
+  };
+  const output = await getTempFilePath();
+  const audit = await getTempFilePath();
+
+  for (let [deduplicateStrategy, expected] of [
+    [
+      "KeepFirst",
+      ["1\tleft\tsuperseded\t1\tAnn", "1\tright\tsuperseded\t1\tAnna"],
+    ],
+    ["RemoveSimilar", ["1\tleft\texact_duplicate\t1\tAnn"]],
+    [
+      "Reduce",
+      [
+        "1\tleft\tfolded_into_reduce\t1\tAnn",
+        "1\tleft\tfolded_into_reduce\t1\tAnn",
+        "1\tright\tfolded_into_reduce\t1\tAnna",
+      ],
+    ],
+  ]) {
+    await merge(left, right, {
+      ...options,
+      deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+      auditOutput: audit,
+      output,
+    });
+    t.deepEqual((await fs.readFile(audit, { encoding: "ascii" })).split("\n"), [
+      "__key\t__side\t__reason\tkey\tname",
+      ...expected,
+      "",
+    ]);
+  }
+
+  await fs.rm(output);
+  await fs.rm(audit);
+});
+
+test(`test custom strategy`, async (t) => {
+  const left = Buffer.from("key\tname\n1\tAnn\n1\tAnnie\n2\tBob\n3\tCid\n");
+  const right = Buffer.from("key\tname\n1\tAnna\n3\t\n");
+  const output = await getTempFilePath();
+  const batches = [];
+
+  await merge(left, right, {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Custom,
+    leftKey: "key",
+    rightKey: "key",
+    groupBatchSize: 2,
+    groupCallback: (groups) => {
+      batches.push(groups.map(({ key, left, right }) => [key, left.length, right.length]));
+      return groups.flatMap(({ key, left, right }) =>
+        key === "2" ? [] : [{ key, name: [...left, ...right].map((row) => row.name).join("+") }]
+      );
+    },
+    output,
+  });
+
+  t.deepEqual(batches, [[["1", 2, 1]], [["2", 1, 0], ["3", 1, 1]]]);
+  t.is(await fs.readFile(output, { encoding: "ascii" }), "key\tname\n1\tAnn+Annie+Anna\n3\tCid+\n");
+
+  t.throws(
+    () =>
+      merge(left, right, {
+        mergeStrategy: MergeStrategy.Or,
+        deduplicateStrategy: DeduplicateStrategy.Custom,
+        leftKey: "key",
+        rightKey: "key",
+        output,
+      }),
+    { message: "groupCallback is required for DeduplicateStrategy.Custom" }
+  );
+
+  const rows = [];
+  for await (const batch of mergeRows(left, right, {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Custom,
+    leftKey: "key",
+    rightKey: "key",
+    groupCallback: (groups) => groups.map(({ left, right }) => [...left, ...right][0]),
+  })) {
+    rows.push(...batch);
+  }
+  t.deepEqual(rows, [
+    { key: "1", name: "Ann" },
+    { key: "2", name: "Bob" },
+    { key: "3", name: "Cid" },
+  ]);
+
+  await fs.rm(output);
+});
+
+test(`test custom strategy rejects on callback errors`, async (t) => {
+  const left = Buffer.from("key\tname\n1\tAnn\n2\tBob\n");
+  const right = Buffer.from("key\tname\n1\tAnna\n");
+  const options = {
+    mergeStrategy: MergeStrategy.Or,
+    deduplicateStrategy: DeduplicateStrategy.Custom,
+    leftKey: "key",
+    rightKey: "key",
+    output: await getTempFilePath(),
+  };
+
+  await t.throwsAsync(
+    merge(left, right, {
+      ...options,
+      groupCallback: () => {
+        throw new Error("no rows for you");
+      },
+    }),
+    { message: /^groupCallback failed: .*no rows for you/ }
+  );
+  await t.throwsAsync(
+    merge(left, right, { ...options, groupCallback: () => [{ key: "1", count: 1 }] }),
+    { message: /^groupCallback failed: / }
+  );
+  await t.throwsAsync(
+    merge(left, right, { ...options, groupCallback: () => 1 }),
+    { message: /^groupCallback failed: / }
+  );
+});
+
+test(`test group count columns`, async (t) => {
+  const left = Buffer.from("key\tname\n1\tAnn\n1\tAnn\n2\tBob\n");
+  const right = Buffer.from("key\tname\n1\tAnna\n");
+  const output = await getTempFilePath();
+
+  for (let [deduplicateStrategy, expected] of [
+    ["KeepFirst", ["1\tAnn\t3\t2\t1", "2\tBob\t1\t1\t0"]],
+    ["RemoveSimilar", ["1\tAnn\t2\t2\t0", "1\tAnna\t1\t0\t1", "2\tBob\t1\t1\t0"]],
+    ["Reduce", ["1\tAnna\t3\t2\t1", "2\tBob\t1\t1\t0"]],
+  ]) {
+    await merge(left, right, {
+      mergeStrategy: MergeStrategy.Or,
+      deduplicateStrategy: DeduplicateStrategy[deduplicateStrategy],
+      leftKey: "key",
+      rightKey: "key",
+      tieBreak: TieBreak.LeftFirst,
+      groupCountColumns: true,
+      output,
+    });
+    t.deepEqual((await fs.readFile(output, { encoding: "ascii" })).split("\n"), [
+      "key\tname\t__group_size\t__group_left\t__group_right",
+      ...expected,
+      "",
+    ]);
+  }
+
+  t.throws(
+    () =>
+      merge(left, right, {
+        mergeStrategy: MergeStrategy.Or,
+        deduplicateStrategy: DeduplicateStrategy.Custom,
+        leftKey: "key",
+        rightKey: "key",
+        groupCountColumns: true,
+        groupCallback: (groups) => [],
+        output,
+      }),
+    { message: "groupCountColumns is not supported by DeduplicateStrategy.Custom" }
+  );
+
+  await fs.rm(output);
+});
@@ -3,7 +3,16 @@ import fs from "fs/promises";
 import path from "path";
 import os from "os";
 
-import { Comparison, FieldType, Filter, Transform } from "../index.js";
+import {
+  AggregationFunction,
+  Comparison,
+  DeduplicateStrategy,
+  FieldType,
+  Filter,
+  InvalidValuePolicy,
+  Transform,
+  deduplicate,
+} from "../index.js";
 
 async function getTempFilePath() {
   const tempDir = await fs.mkdtemp(path.join(os.tmpdir(), "tmp-"));
@@ -31,3 +40,313 @@ test("Test transform", async (t) => {
   t.snapshot(await fs.readFile(output, { encoding: "ascii" }));
   await fs.rm(output);
 });
+
+test("Test deduplicate", async (t) => {
+  const output = await getTempFilePath();
+
+  await deduplicate("./__test__/fixtures/list1-sorted.csv", {
+    key: "key",
+    strategy: DeduplicateStrategy.Reduce,
+    aggregations: [{ column: "feature2_left", function: AggregationFunction.Sum }],
+    output,
+  });
+  const lines = (await fs.readFile(output, { encoding: "ascii" })).split("\n");
+  t.is(lines[0], "key\tfeature_left\tfeature2_left");
+  t.is(lines[2], "2\t300\t7");
+  t.is(lines[7], "78\t900\t8");
+  t.is(lines.length, 12);
+
+  await t.throwsAsync(
+    deduplicate("./__test__/fixtures/list1-sorted.csv", {
+      key: "id",
+      strategy: DeduplicateStrategy.KeepFirst,
+      output,
+    }),
+    { message: "Not found key field id" }
+  );
+  await fs.rm(output);
+});
+
+test("Test transform deduplication", async (t) => {
+  const output = await getTempFilePath();
+  const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+
+  transform.addFilter(new Filter("key", "78", FieldType.Number, Comparison.Eq));
+  transform.setDeduplication({ key: "key", strategy: DeduplicateStrategy.KeepFirst });
+  await transform.saveCsv(output);
+
+  t.is(
+    await fs.readFile(output, { encoding: "ascii" }),
+    "key\tfeature_left\tfeature2_left\n78\t900\t2\n"
+  );
+  await fs.rm(output);
+});
+
+test("Test custom deduplication rejects on callback errors", async (t) => {
+  const output = await getTempFilePath();
+  const options = { key: "key", strategy: DeduplicateStrategy.Custom, output };
+
+  await t.throwsAsync(
+    deduplicate("./__test__/fixtures/list1-sorted.csv", {
+      ...options,
+      groupCallback: () => {
+        throw new Error("no rows for you");
+      },
+    }),
+    { message: "groupCallback failed: Error: no rows for you" }
+  );
+
+  const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+  transform.setDeduplication({ ...options, groupCallback: () => [{ key: 1 }] });
+  await t.throwsAsync(transform.saveCsv(output), { message: /^groupCallback failed: / });
+});
+
+test("Test distinct on unsorted file", async (t) => {
+  const input = await getTempFilePath();
+  const output = await getTempFilePath();
+  await fs.writeFile(input, "key\tname\n3\tCid\n1\tAnn\n3\tCid\n2\tBob\n1\tAnna\n1\tAnn\n");
+
+  for (let groupMemoryLimit of [undefined, 1]) {
+    await deduplicate(input, { strategy: DeduplicateStrategy.Distinct, groupMemoryLimit, output });
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      "key\tname\n3\tCid\n1\tAnn\n2\tBob\n1\tAnna\n"
+    );
+
+    await deduplicate(input, {
+      strategy: DeduplicateStrategy.Distinct,
+      similarityColumns: ["key"],
+      groupMemoryLimit,
+      output,
+    });
+    t.is(
+      await fs.readFile(output, { encoding: "ascii" }),
+      "key\tname\n3\tCid\n1\tAnn\n2\tBob\n"
+    );
+  }
+
+  t.throws(() => deduplicate(input, { strategy: DeduplicateStrategy.KeepFirst, output }), {
+    message: "key is required",
+  });
+  await fs.rm(input);
+  await fs.rm(output);
+});
+
+test("Test filter tree", async (t) => {
+  const output = await getTempFilePath();
+  const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+
+  transform.addFilter(
+    Filter.or([
+      new Filter("key", "2", FieldType.Number, Comparison.Le),
+      Filter.and([
+        new Filter("key", "78", FieldType.Number, Comparison.Ge),
+        Filter.not(new Filter("feature_left", "900", FieldType.String, Comparison.Eq)),
+      ]),
+    ])
+  );
+  await transform.saveCsv(output);
+
+  const keys = (await fs.readFile(output, { encoding: "ascii" }))
+    .trim()
+    .split("\n")
+    .slice(1)
+    .map((line) => line.split("\t").slice(0, 2).join(":"));
+  t.deepEqual(keys, ["1:100", "2:150", "2:200", "2:150", "2:300", "78:950", "88:700", "300:7000"]);
+  await fs.rm(output);
+});
+
+test("Test filter comparisons", async (t) => {
+  const output = await getTempFilePath();
+  const keysFor = async (filter) => {
+    const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+    transform.addFilter(filter);
+    await transform.saveCsv(output);
+    return (await fs.readFile(output, { encoding: "ascii" }))
+      .trim()
+      .split("\n")
+      .slice(1)
+      .map((line) => line.split("\t")[0]);
+  };
+
+  t.deepEqual(
+    await keysFor(new Filter("key", "", FieldType.Number, Comparison.In, { values: ["6", "99"] })),
+    ["6", "99"]
+  );
+  t.deepEqual(
+    await keysFor(new Filter("key", "7", FieldType.Number, Comparison.Between, { to: "43" })),
+    ["7", "12", "43"]
+  );
+  t.deepEqual(await keysFor(new Filter("key", "^3", FieldType.String, Comparison.Regex)), ["300"]);
+  t.deepEqual(
+    await keysFor(new Filter("feature_left", "9", FieldType.String, Comparison.StartsWith)),
+    ["78", "78", "78", "78", "99"]
+  );
+  await fs.rm(output);
+});
+
+test("Test typed filters", async (t) => {
+  const input = await getTempFilePath();
+  const output = await getTempFilePath();
+  await fs.writeFile(
+    input,
+    "id\tprice\tsignup\trenewal\tactive\n" +
+      "1\t9.99\t2023-12-31\t31/12/2024\ttrue\n" +
+      "2\t10.5\t2024-01-01\t01/01/2025\tno\n" +
+      "3\t9.989\t2024-02-29\t28/02/2025\tYes\n"
+  );
+  const idsFor = async (filter) => {
+    const transform = new Transform(input);
+    transform.addFilter(filter);
+    await transform.saveCsv(output);
+    return (await fs.readFile(output, { encoding: "ascii" }))
+      .trim()
+      .split("\n")
+      .slice(1)
+      .map((line) => line.split("\t")[0]);
+  };
+
+  t.deepEqual(await idsFor(new Filter("price", "9.99", FieldType.Float, Comparison.Ge)), ["1", "2"]);
+  t.deepEqual(await idsFor(new Filter("price", "9.990", FieldType.Decimal, Comparison.Eq)), ["1"]);
+  t.deepEqual(await idsFor(new Filter("signup", "2024-01-01", FieldType.Date, Comparison.Lt)), ["1"]);
+  t.deepEqual(
+    await idsFor(
+      new Filter("renewal", "01/02/2025", FieldType.Date, Comparison.Ge, { format: "%d/%m/%Y" })
+    ),
+    ["3"]
+  );
+  t.deepEqual(await idsFor(new Filter("active", "true", FieldType.Boolean, Comparison.Eq)), ["1", "3"]);
+
+  await fs.rm(input);
+  await fs.rm(output);
+});
+
+test("Test invalid filter values", async (t) => {
+  const input = await getTempFilePath();
+  const output = await getTempFilePath();
+  await fs.writeFile(input, "id\tamount\n1\t10\n2\t\n3\t40\n");
+
+  const transform = new Transform(input);
+  t.throws(() => transform.addFilter(new Filter("amount", "ten", FieldType.Number, Comparison.Lt)), {
+    message: "Invalid Number filter value for amount: ten",
+  });
+
+  transform.addFilter(
+    new Filter("amount", "20", FieldType.Number, Comparison.Lt, { onInvalid: InvalidValuePolicy.Match })
+  );
+  await transform.saveCsv(output);
+  t.is(await fs.readFile(output, { encoding: "ascii" }), "id\tamount\n1\t10\n2\t\n");
+
+  const failing = new Transform(input);
+  failing.addFilter(
+    new Filter("amount", "20", FieldType.Number, Comparison.Lt, { onInvalid: InvalidValuePolicy.Fail })
+  );
+  await t.throwsAsync(failing.saveCsv(output), { message: "Invalid Number value for amount on line 3: " });
+
+  await fs.rm(input);
+  await fs.rm(output);
+});
+
+test("Test column comparison filters", async (t) => {
+  const input = await getTempFilePath();
+  const output = await getTempFilePath();
+  await fs.writeFile(
+    input,
+    "id\tstart_date\tend_date\n1\t2024-01-10\t2024-01-05\n2\t2024-01-10\t2024-02-01\n3\t2024-03-01\t2024-03-01\n"
+  );
+
+  const transform = new Transform(input);
+  transform.addFilter(
+    new Filter("end_date", "", FieldType.Date, Comparison.Ge, { valueColumn: "start_date" })
+  );
+  await transform.saveCsv(output);
+  const ids = (await fs.readFile(output, { encoding: "ascii" }))
+    .trim()
+    .split("\n")
+    .slice(1)
+    .map((line) => line.split("\t")[0]);
+  t.deepEqual(ids, ["2", "3"]);
+
+  await fs.rm(input);
+  await fs.rm(output);
+});
+
+test("Test key set filters", async (t) => {
+  const input = await getTempFilePath();
+  const suppressionList = await getTempFilePath();
+  const output = await getTempFilePath();
+  await fs.writeFile(input, "user_id\tname\n3\tc\n1\ta\n2\tb\n");
+  await fs.writeFile(suppressionList, "email,user_id\nb@example.com,2\nc@example.com,3\n");
+  const keySet = new Filter("user_id", suppressionList, FieldType.String, Comparison.InKeySet, {
+    keyColumn: "user_id",
+    keyDelimiter: ",".charCodeAt(0),
+    hashKeys: true,
+  });
+  const names = async (filter) => {
+    const transform = new Transform(input);
+    transform.addFilter(filter);
+    await transform.saveCsv(output);
+    return (await fs.readFile(output, { encoding: "ascii" }))
+      .trim()
+      .split("\n")
+      .slice(1)
+      .map((line) => line.split("\t")[1]);
+  };
+
+  t.deepEqual(await names(keySet), ["c", "b"]);
+  t.deepEqual(await names(Filter.not(keySet)), ["a"]);
+
+  await fs.rm(input);
+  await fs.rm(suppressionList);
+  await fs.rm(output);
+});
+
+test("Test row filter", async (t) => {
+  const output = await getTempFilePath();
+  const batchSizes = [];
+  const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+  transform.addFilter(new Filter("key", "78", FieldType.Number, Comparison.Ge));
+  transform.appendLineNumber();
+  transform.setRowFilter((rows) => {
+    batchSizes.push(rows.length);
+    return rows.map((row) => Number(row.feature_left) % 100 === 0 && row.key !== "300");
+  }, 3);
+  await transform.saveCsv(output);
+
+  t.deepEqual(batchSizes, [3, 3, 1]);
+  t.deepEqual(
+    (await fs.readFile(output, { encoding: "ascii" })).trim().split("\n"),
+    [
+      "key\tfeature_left\tfeature2_left\t__line_number",
+      "78\t900\t2\t10",
+      "78\t900\t2\t12",
+      "78\t900\t2\t13",
+      "88\t700\t2\t14",
+      "99\t900\t2\t15",
+    ]
+  );
+
+  await fs.rm(output);
+});
+
+test("Test row filter errors reject saveCsv", async (t) => {
+  const output = await getTempFilePath();
+  const rowFilters = [
+    [
+      () => {
+        throw new Error("bad row");
+      },
+      "Row filter failed: Error: bad row",
+    ],
+    [(rows) => rows.map(() => 1), /^Row filter failed: /],
+    [() => "keep", /^Row filter failed: /],
+    [(rows) => rows.slice(1).map(() => true), "Row filter returned 2 results for 3 rows"],
+  ];
+  for (const [rowFilter, message] of rowFilters) {
+    const transform = new Transform("./__test__/fixtures/list1-sorted.csv");
+    transform.setRowFilter(rowFilter, 3);
+    await t.throwsAsync(transform.saveCsv(output), { message });
+  }
+
+  await fs.rm(output, { force: true });
+});
@@ -4,15 +4,83 @@
 /* auto-generated by NAPI-RS */
 
 export const enum FieldType {
+  /** Integer. */
   Number = 0,
-  String = 1
+  String = 1,
+  Float = 2,
+  /** Compared exactly, unlike `Float`. */
+  Decimal = 3,
+  /** `%Y-%m-%d` unless `options.format` says otherwise. */
+  Date = 4,
+  /** `%Y-%m-%dT%H:%M:%S` unless `options.format` says otherwise. */
+  DateTime = 5,
+  /** true/false, yes/no, t/f, y/n or 1/0, in any case. */
+  Boolean = 6
 }
 export const enum Comparison {
   Eq = 0,
   Lt = 1,
   Le = 2,
   Gt = 3,
-  Ge = 4
+  Ge = 4,
+  Ne = 5,
+  /** Equal to one of `options.values`. */
+  In = 6,
+  Contains = 7,
+  StartsWith = 8,
+  EndsWith = 9,
+  /** Matches the regular expression in `value`, anywhere in the cell. */
+  Regex = 10,
+  IsEmpty = 11,
+  IsNotEmpty = 12,
+  /** Between `value` and `options.to`, both included. */
+  Between = 13,
+  /**
+   * Text of the cell is one of the keys in the file at `value`, which does not
+   * need to be sorted. Wrap in `Filter.not` to remove matching rows.
+   */
+  InKeySet = 14
+}
+/** What a filter does with a cell that does not parse as its field type. */
+export const enum InvalidValuePolicy {
+  NonMatch = 0,
+  Match = 1,
+  /** Fails the transform with the line of the cell. */
+  Fail = 2
+}
+/** Operands of comparisons that need more than `value`. */
+export interface FilterOptions {
+  /** Values accepted by `Comparison.In`. */
+  values?: Array<string>
+  /** Upper bound of `Comparison.Between`. */
+  to?: string
+  /**
+   * Format of `Date` and `DateTime` values, built from `%Y`, `%m`, `%d`,
+   * `%H`, `%M` and `%S`.
+   */
+  format?: string
+  /** `NonMatch` by default. */
+  onInvalid?: InvalidValuePolicy
+  /**
+   * Column compared with instead of `value`, with `Eq`, `Ne`, `Lt`, `Le`,
+   * `Gt`, `Ge`, `Contains`, `StartsWith` and `EndsWith`.
+   */
+  valueColumn?: string
+  /**
+   * Column of the `InKeySet` file holding the keys. Without it every line
+   * of the file is a key.
+   */
+  keyColumn?: string
+  /**
+   * Delimiter of the `InKeySet` file when `keyColumn` is set, tab by
+   * default.
+   */
+  keyDelimiter?: number
+  /**
+   * Keeps 64-bit hashes of the `InKeySet` keys instead of the keys, which
+   * saves memory on long keys at the cost of rare false matches.
+   */
+  hashKeys?: boolean
 }
 export const enum MergeStrategy {
   Or = 0,
@@ -25,28 +93,262 @@ export const enum DeduplicateStrategy {
   RemoveSimilar = 2,
   Reduce = 3,
   CrossJoin = 4,
-  CrossJoinAndRemoveSimilar = 5
+  CrossJoinAndRemoveSimilar = 5,
+  KeepLast = 6,
+  /**
+   * Hands the rows of each key to `groupCallback`, which returns the rows
+   * to write.
+   */
+  Custom = 7,
+  /**
+   * Keeps the first row of every value of `similarityColumns`, whole rows
+   * by default, wherever it occurs. Does not need rows sorted by key,
+   * though `merge` only takes unsorted input with `MergeStrategy.Or`, as
+   * `And` and `AndNot` still match rows by key.
+   */
+  Distinct = 8
+}
+export const enum KeyNormalization {
+  Trim = 0,
+  Lowercase = 1,
+  StripLeadingZeros = 2,
+  Nfkc = 3,
+  DigitsOnly = 4
+}
+/**
+ * Handling of rows whose key is empty or one of `nullTokens`. Such rows may
+ * appear anywhere in an otherwise sorted input.
+ */
+export const enum EmptyKeyPolicy {
+  /**
+   * Empty keys of both sides match each other, as one more key written
+   * after all others.
+   */
+  MatchEachOther = 0,
+  NeverMatch = 1,
+  Drop = 2,
+  Reject = 3
+}
+/**
+ * Order in which left and right rows sharing a key reach the deduplicate
+ * strategy. With `Reduce` the later row wins, with `KeepFirst` the earlier one.
+ */
+export const enum TieBreak {
+  /**
+   * Rows are passed on in the order the merge loop reads them, which
+   * interleaves both sides.
+   */
+  InputOrder = 0,
+  /** All left rows of a key go first, then all right rows. */
+  LeftFirst = 1,
+  /** All right rows of a key go first, then all left rows. */
+  RightFirst = 2
+}
+/**
+ * `Sum`, `Min`, `Max` and `Mean` are exact while a group's values are plain
+ * decimals like `19.99`, and use floating point otherwise.
+ */
+export const enum AggregationFunction {
+  Sum = 0,
+  Min = 1,
+  Max = 2,
+  Count = 3,
+  CountDistinct = 4,
+  First = 5,
+  Last = 6,
+  Concat = 7,
+  Mean = 8
+}
+/**
+ * Per-column aggregation applied by the `Reduce` strategy. Columns without
+ * one keep the last non-empty value of the group.
+ */
+export interface ColumnAggregation {
+  column: string
+  function: AggregationFunction
+  /** Separator used by `Concat`, empty by default. */
+  separator?: string
+}
+/**
+ * How `Reduce` and `CrossJoin` pick a column value when the left and right
+ * rows of a key disagree.
+ */
+export const enum ConflictPolicy {
+  LastNonEmpty = 0,
+  PreferLeft = 1,
+  PreferRight = 2,
+  FirstNonEmpty = 3,
+  /** Fails the merge on the first conflict. */
+  Error = 4,
+  /**
+   * Keeps the last non-empty value and writes the conflict to
+   * `conflictReportOutput`.
+   */
+  Report = 5
+}
+export interface ColumnConflictPolicy {
+  column: string
+  policy: ConflictPolicy
+}
+export const enum CrossJoinLimitAction {
+  /** Fails the merge. */
+  Fail = 0,
+  /** Keeps the first rows of the key up to the limit. */
+  Truncate = 1,
+  /** Drops all rows of the key. */
+  Skip = 2
+}
+/** Bounds on what `CrossJoin` materializes for a single key. */
+export interface CrossJoinLimits {
+  /** Rows of both sides sharing a key. */
+  maxGroupSize?: number
+  /** Left × right combinations written for a key. */
+  maxProduct?: number
+  /** `Fail` by default. */
+  action?: CrossJoinLimitAction
+}
+export interface MergeStats {
+  /** Keys truncated or skipped because of `crossJoinLimits`. */
+  crossJoinLimitedKeys: Array<string>
+  /** Why each of `crossJoinLimitedKeys` was limited. */
+  warnings: Array<string>
+}
+/** Rows sharing a key, as passed to `groupCallback`. */
+export interface KeyGroup {
+  key: string
+  left: Array<Record<string, string>>
+  right: Array<Record<string, string>>
+}
+export const enum RowFormat {
+  Object = 0,
+  Array = 1
 }
 export interface MergeOptions {
-  output: string
+  /** Required by `merge`, ignored by `mergeRows`. */
+  output?: string
   mergeStrategy: MergeStrategy
   deduplicateStrategy: DeduplicateStrategy
   leftKey: string
   rightKey: string
   isNumberKey?: boolean
+  /**
+   * Steps applied to both keys before comparing them. Inputs must be
+   * sorted by the normalized key, the merge fails on a key that sorts
+   * before the previous one.
+   */
+  keyNormalization?: Array<KeyNormalization>
+  emptyKeyPolicy?: EmptyKeyPolicy
+  emptyKeyRejectOutput?: string
+  /**
+   * Key cells treated as empty keys. Matched after `keyNormalization`,
+   * which is applied to the tokens as well.
+   */
+  nullTokens?: Array<string>
+  appendLineNumber?: boolean
+  appendSourcePath?: boolean
+  tieBreak?: TieBreak
+  aggregations?: Array<ColumnAggregation>
+  conflictPolicies?: Array<ColumnConflictPolicy>
+  conflictReportOutput?: string
+  crossJoinLimits?: CrossJoinLimits
+  /**
+   * Bytes of a key group that `RemoveSimilar`, `Reduce` and `CrossJoin`
+   * keep in memory before spilling it to temporary files, or of the values
+   * `Distinct` has seen. Also bounds each side of a key held back by
+   * `tieBreak`. Unlimited by default.
+   */
+  groupMemoryLimit?: number
+  /**
+   * Columns `RemoveSimilar`, `CrossJoinAndRemoveSimilar` and `Distinct`
+   * compare rows on, all by default. Exclusive with
+   * `similarityIgnoreColumns`.
+   */
+  similarityColumns?: Array<string>
+  /** Columns left out when comparing rows for similarity. */
+  similarityIgnoreColumns?: Array<string>
+  /**
+   * Appends `__group_size`, `__group_left` and `__group_right`, the input
+   * rows each output row stands for. `CrossJoin` repeats the counts of the
+   * key on every combination. Not supported by `Custom`.
+   */
+  groupCountColumns?: boolean
+  /**
+   * File listing the rows `KeepFirst`, `KeepLast`, `RemoveSimilar` and
+   * `Reduce` dropped, with their key, side and the reason.
+   */
+  auditOutput?: string
+  /** Rows per batch yielded by `mergeRows`, 1000 by default. */
+  batchSize?: number
+  rowFormat?: RowFormat
   outputHeaderCallback?: (columnName: string) => string | undefined
+  /**
+   * Resolves key groups for `DeduplicateStrategy.Custom`. Called with a
+   * batch of groups, it returns the rows to write for all of them, keyed
+   * by output column. It must return synchronously. A throw, or rows that
+   * are not objects of strings, reject the merge.
+   */
+  groupCallback?: (groups: Array<KeyGroup>) => Array<Record<string, string>>
+  /**
+   * Rows collected before `groupCallback` is called, 1000 by default.
+   * Groups are never split between calls.
+   */
+  groupBatchSize?: number
 }
-export function merge(leftPath: string, rightPath: string, options: MergeOptions): Promise<void>
+/** Rows of a single file sharing a key, sorted by that key. */
+export interface DeduplicateOptions {
+  /** Required by `deduplicate`, ignored by `Transform.setDeduplication`. */
+  output?: string
+  /**
+   * Column the file is sorted by, required by all strategies but
+   * `Distinct`.
+   */
+  key?: string
+  strategy: DeduplicateStrategy
+  aggregations?: Array<ColumnAggregation>
+  similarityColumns?: Array<string>
+  similarityIgnoreColumns?: Array<string>
+  groupMemoryLimit?: number
+  auditOutput?: string
+  groupCountColumns?: boolean
+  groupCallback?: (groups: Array<KeyGroup>) => Array<Record<string, string>>
+  groupBatchSize?: number
+}
+export function merge(leftInput: string | Buffer | NodeJS.ReadableStream, rightInput: string | Buffer | NodeJS.ReadableStream, options: MergeOptions): Promise<MergeStats>
+export function mergeRows(leftInput: string | Buffer | NodeJS.ReadableStream, rightInput: string | Buffer | NodeJS.ReadableStream, options: MergeOptions): AsyncIterableIterator<Array<Record<string, string>> | Array<Array<string>>>
+/** Deduplicates a single tab-separated file sorted by `options.key`. */
+export function deduplicate(path: string, options: DeduplicateOptions): Promise<void>
 export type JsFilter = Filter
 export class Filter {
-  constructor(field: string, value: string, fieldType?: FieldType | undefined | null, comparison?: Comparison | undefined | null)
+  constructor(field: string, value: string, fieldType?: FieldType | undefined | null, comparison?: Comparison | undefined | null, options?: FilterOptions | undefined | null)
+  /** Matches rows matching all of the filters. */
+  static and(filters: Array<Filter>): Filter
+  /** Matches rows matching any of the filters. */
+  static or(filters: Array<Filter>): Filter
+  /** Matches rows the filter does not match. */
+  static not(filter: Filter): Filter
 }
 export type JsTransform = Transform
 export class Transform {
   constructor(path: string)
   withDelimiter(delimiter: number): void
   appendLineNumber(): void
+  /** Throws on filter values that do not parse as the field type. */
   addFilter(filter: Filter): void
+  /**
+   * Keeps the rows, out of those matching the filters, for which
+   * `rowFilter` returns true. It is called with `batchSize` rows at a
+   * time, 1000 by default, keyed by input column, and returns one boolean
+   * per row. It must return synchronously. A throw, or a result other than
+   * one boolean per row, rejects `saveCsv`.
+   */
+  setRowFilter(rowFilter: (rows: Array<Record<string, string>>) => Array<boolean>, batchSize?: number): void
+  /** Deduplicates the rows written by `saveCsv`, see `deduplicate`. */
+  setDeduplication(options: DeduplicateOptions): void
   setColumnsTransform(columnTransform: (...args: any[]) => any): void
   saveCsv(path: string): Promise<void>
 }
+export class MergeRowsIterator {
+  next(): Promise<IteratorResult<Array<Record<string, string>> | Array<Array<string>>>>
+  /** Stops the merge early, e.g. on `break` inside `for await`. */
+  return(): IteratorResult<undefined>
+}
@@ -246,12 +246,23 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { FieldType, Comparison, Filter, Transform, MergeStrategy, DeduplicateStrategy, merge } = nativeBinding
+const { FieldType, Comparison, InvalidValuePolicy, Filter, Transform, MergeStrategy, DeduplicateStrategy, KeyNormalization, EmptyKeyPolicy, TieBreak, AggregationFunction, ConflictPolicy, CrossJoinLimitAction, RowFormat, merge, mergeRows, MergeRowsIterator, deduplicate } = nativeBinding
 
 module.exports.FieldType = FieldType
 module.exports.Comparison = Comparison
+module.exports.InvalidValuePolicy = InvalidValuePolicy
 module.exports.Filter = Filter
 module.exports.Transform = Transform
 module.exports.MergeStrategy = MergeStrategy
 module.exports.DeduplicateStrategy = DeduplicateStrategy
+module.exports.KeyNormalization = KeyNormalization
+module.exports.EmptyKeyPolicy = EmptyKeyPolicy
+module.exports.TieBreak = TieBreak
+module.exports.AggregationFunction = AggregationFunction
+module.exports.ConflictPolicy = ConflictPolicy
+module.exports.CrossJoinLimitAction = CrossJoinLimitAction
+module.exports.RowFormat = RowFormat
 module.exports.merge = merge
+module.exports.mergeRows = mergeRows
+module.exports.MergeRowsIterator = MergeRowsIterator
+module.exports.deduplicate = deduplicate
@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::io;
+
+use crate::AggregationFunction;
+
+use super::value::Decimal;
+
+/// Aggregation of one output column, resolved against the output headers.
+pub struct Aggregation {
+    pub index: usize,
+    pub column: String,
+    pub function: AggregationFunction,
+    pub separator: String,
+}
+
+impl Aggregation {
+    /// Aggregates the non-empty values of the column within a key group.
+    pub fn apply(&self, values: &[&[u8]]) -> Result<Vec<u8>, csv::Error> {
+        let values: Vec<&[u8]> = values
+            .iter()
+            .copied()
+            .filter(|value| !value.is_empty())
+            .collect();
+
+        let result = match self.function {
+            AggregationFunction::First =>
+                values
+                    .first()
+                    .map(|value| value.to_vec())
+                    .unwrap_or_default(),
+            AggregationFunction::Last =>
+                values
+                    .last()
+                    .map(|value| value.to_vec())
+                    .unwrap_or_default(),
+            AggregationFunction::Count => values.len().to_string().into_bytes(),
+            AggregationFunction::CountDistinct =>
+                values.iter().collect::<HashSet<_>>().len().to_string().into_bytes(),
+            AggregationFunction::Concat => values.join(self.separator.as_bytes()),
+            AggregationFunction::Sum =>
+                match decimals(&values).and_then(|decimals| Decimal::checked_sum(&decimals)) {
+                    Some(sum) => format_decimal(sum),
+                    None => format_number(sum(&self.numbers(&values)?)),
+                }
+            AggregationFunction::Min =>
+                match decimals(&values) {
+                    Some(decimals) =>
+                        decimals.into_iter().min().map(format_decimal).unwrap_or_default(),
+                    None =>
+                        self
+                            .numbers(&values)?
+                            .into_iter()
+                            .reduce(f64::min)
+                            .map(format_number)
+                            .unwrap_or_default(),
+                }
+            AggregationFunction::Max =>
+                match decimals(&values) {
+                    Some(decimals) =>
+                        decimals.into_iter().max().map(format_decimal).unwrap_or_default(),
+                    None =>
+                        self
+                            .numbers(&values)?
+                            .into_iter()
+                            .reduce(f64::max)
+                            .map(format_number)
+                            .unwrap_or_default(),
+                }
+            AggregationFunction::Mean if values.is_empty() => vec![],
+            AggregationFunction::Mean => {
+                let mean = decimals(&values).and_then(|decimals| {
+                    let count = u32::try_from(decimals.len()).ok()?;
+                    Decimal::checked_sum(&decimals)?.checked_div(count)
+                });
+                match mean {
+                    Some(mean) => format_decimal(mean),
+                    None => {
+                        let numbers = self.numbers(&values)?;
+                        format_number(sum(&numbers) / (numbers.len() as f64))
+                    }
+                }
+            }
+        };
+        Ok(result)
+    }
+
+    /// Floating point values, for when `decimals` cannot be used.
+    fn numbers(&self, values: &[&[u8]]) -> Result<Vec<f64>, csv::Error> {
+        values
+            .iter()
+            .map(|value| {
+                std::str
+                    ::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<f64>().ok())
+                    .filter(|number| number.is_finite())
+                    .ok_or_else(|| {
+                        io::Error
+                            ::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "Cannot aggregate column {}: {:?} is not a number",
+                                    self.column,
+                                    String::from_utf8_lossy(value)
+                                )
+                            )
+                            .into()
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Exact values while all are plain decimals, so that amounts of money add up
+/// without floating point artifacts. `None` for anything else, like `1e3`.
+fn decimals(values: &[&[u8]]) -> Option<Vec<Decimal>> {
+    values
+        .iter()
+        .map(|value| Decimal::parse(std::str::from_utf8(value).ok()?.trim()))
+        .collect()
+}
+
+/// `Iterator::sum` starts from -0.0, which would print as "-0" for empty groups.
+fn sum(numbers: &[f64]) -> f64 {
+    numbers.iter().fold(0.0, |sum, number| sum + number)
+}
+
+fn format_number(number: f64) -> Vec<u8> {
+    number.to_string().into_bytes()
+}
+
+fn format_decimal(number: Decimal) -> Vec<u8> {
+    number.to_string().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(function: AggregationFunction, values: &[&str]) -> Result<String, csv::Error> {
+        let aggregation = Aggregation {
+            index: 0,
+            column: "amount".to_string(),
+            function,
+            separator: "|".to_string(),
+        };
+        let values: Vec<&[u8]> = values
+            .iter()
+            .map(|value| value.as_bytes())
+            .collect();
+        aggregation.apply(&values).map(|result| String::from_utf8(result).unwrap())
+    }
+
+    #[test]
+    fn test_numeric_aggregations() {
+        let values = ["2", "", "10", "3.5"];
+        assert_eq!(aggregate(AggregationFunction::Sum, &values).unwrap(), "15.5");
+        assert_eq!(aggregate(AggregationFunction::Min, &values).unwrap(), "2");
+        assert_eq!(aggregate(AggregationFunction::Max, &values).unwrap(), "10");
+        assert_eq!(aggregate(AggregationFunction::Mean, &["1", "2"]).unwrap(), "1.5");
+        assert_eq!(aggregate(AggregationFunction::Mean, &["", ""]).unwrap(), "");
+        assert_eq!(aggregate(AggregationFunction::Sum, &[""]).unwrap(), "0");
+    }
+
+    #[test]
+    fn test_decimal_aggregations() {
+        let amounts = ["0.1", "0.2", "0.10"];
+        assert_eq!(aggregate(AggregationFunction::Sum, &amounts).unwrap(), "0.4");
+        assert_eq!(aggregate(AggregationFunction::Min, &amounts).unwrap(), "0.1");
+        assert_eq!(aggregate(AggregationFunction::Max, &amounts).unwrap(), "0.2");
+        assert_eq!(aggregate(AggregationFunction::Mean, &["0.1", "0.2"]).unwrap(), "0.15");
+        assert_eq!(
+            aggregate(AggregationFunction::Sum, &["9007199254740993", "1"]).unwrap(),
+            "9007199254740994"
+        );
+        assert_eq!(aggregate(AggregationFunction::Sum, &["-1.50", " 1.5 "]).unwrap(), "0");
+        // No finite decimal expansion, or not a plain decimal: floating point.
+        assert_eq!(
+            aggregate(AggregationFunction::Mean, &["1", "1", "2"]).unwrap(),
+            "1.3333333333333333"
+        );
+        assert_eq!(aggregate(AggregationFunction::Sum, &["1e3", "0.5"]).unwrap(), "1000.5");
+    }
+
+    #[test]
+    fn test_value_aggregations() {
+        let values = ["b", "", "a", "b"];
+        assert_eq!(aggregate(AggregationFunction::First, &values).unwrap(), "b");
+        assert_eq!(aggregate(AggregationFunction::Last, &values).unwrap(), "b");
+        assert_eq!(aggregate(AggregationFunction::Count, &values).unwrap(), "3");
+        assert_eq!(aggregate(AggregationFunction::CountDistinct, &values).unwrap(), "2");
+        assert_eq!(aggregate(AggregationFunction::Concat, &values).unwrap(), "b|a|b");
+    }
+
+    #[test]
+    fn test_not_a_number() {
+        let err = aggregate(AggregationFunction::Sum, &["1", "x"]).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot aggregate column amount: \"x\" is not a number");
+    }
+}
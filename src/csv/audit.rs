@@ -0,0 +1,72 @@
+use std::fs::File;
+
+use csv::{ ByteRecord, Writer, WriterBuilder };
+
+use super::deduplicate::Side;
+
+pub enum DropReason {
+    /// Identical to a row that was kept.
+    ExactDuplicate,
+    /// Equal to a kept row on the similarity columns only.
+    Similar,
+    /// Another row of the key was kept instead, by `KeepFirst` or `KeepLast`.
+    Superseded,
+    /// Merged with the other rows of its key into one `Reduce` row.
+    FoldedIntoReduce,
+}
+
+impl DropReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::ExactDuplicate => "exact_duplicate",
+            DropReason::Similar => "similar",
+            DropReason::Superseded => "superseded",
+            DropReason::FoldedIntoReduce => "folded_into_reduce",
+        }
+    }
+}
+
+/// Rows dropped by the deduplicate handlers, written as key, side, reason
+/// and then the row itself. Does nothing when no audit output is configured.
+#[derive(Default)]
+pub struct AuditLog {
+    writer: Option<Box<Writer<File>>>,
+}
+
+impl AuditLog {
+    pub fn create(path: &str, headers: &[String]) -> Result<Self, csv::Error> {
+        let mut writer = WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+        let mut audit_headers = vec!["__key".to_string(), "__side".to_string(), "__reason".to_string()];
+        audit_headers.extend_from_slice(headers);
+        writer.write_record(&audit_headers)?;
+        Ok(AuditLog {
+            writer: Some(Box::new(writer)),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        key_value: &[u8],
+        side: &Side,
+        reason: DropReason,
+        row: &ByteRecord
+    ) -> Result<(), csv::Error> {
+        if let Some(writer) = &mut self.writer {
+            let side: &[u8] = match side {
+                Side::Left => b"left",
+                Side::Right => b"right",
+            };
+            let mut record = ByteRecord::from(vec![key_value, side, reason.as_str().as_bytes()]);
+            record.extend(row);
+            writer.write_byte_record(&record)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), csv::Error> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
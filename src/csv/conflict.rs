@@ -0,0 +1,156 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use csv::{ Writer, WriterBuilder };
+
+use crate::ConflictPolicy;
+
+use super::deduplicate::Side;
+
+/// Non-empty values a column takes within a key group.
+#[derive(Default, Clone)]
+pub struct FieldValues<'r> {
+    first: Option<Cow<'r, [u8]>>,
+    last: Option<Cow<'r, [u8]>>,
+    left: Option<Cow<'r, [u8]>>,
+    right: Option<Cow<'r, [u8]>>,
+}
+
+impl<'r> FieldValues<'r> {
+    pub fn push(&mut self, field: &'r [u8], side: &Side) {
+        if !field.is_empty() {
+            self.set(Cow::Borrowed(field), side);
+        }
+    }
+
+    /// Copies the field, for rows that do not outlive the call.
+    pub fn push_owned(&mut self, field: &[u8], side: &Side) {
+        if !field.is_empty() {
+            self.set(Cow::Owned(field.to_vec()), side);
+        }
+    }
+
+    fn set(&mut self, field: Cow<'r, [u8]>, side: &Side) {
+        if self.first.is_none() {
+            self.first = Some(field.clone());
+        }
+        match side {
+            Side::Left => {
+                self.left = Some(field.clone());
+            }
+            Side::Right => {
+                self.right = Some(field.clone());
+            }
+        }
+        self.last = Some(field);
+    }
+
+    fn conflict(&self) -> Option<(&[u8], &[u8])> {
+        match (self.left.as_deref(), self.right.as_deref()) {
+            (Some(left), Some(right)) if left != right => Some((left, right)),
+            _ => None,
+        }
+    }
+}
+
+/// Picks column values for `Reduce` and `CrossJoin` when left and right rows
+/// disagree. Columns without a policy keep the last non-empty value.
+#[derive(Default)]
+pub struct ConflictResolver {
+    policies: HashMap<usize, ConflictPolicy>,
+    headers: Vec<String>,
+    report: Option<Writer<File>>,
+}
+
+impl ConflictResolver {
+    pub fn create(
+        policies: HashMap<usize, ConflictPolicy>,
+        headers: Vec<String>,
+        report_output: Option<&String>
+    ) -> Result<Self, csv::Error> {
+        let needs_report = policies.values().any(|policy| matches!(policy, ConflictPolicy::Report));
+        let report = match (needs_report, report_output) {
+            (false, _) => None,
+            (true, Some(path)) => {
+                let mut writer = WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+                writer.write_record(["key", "column", "left_value", "right_value"])?;
+                Some(writer)
+            }
+            (true, None) => {
+                return Err(
+                    io::Error
+                        ::new(
+                            io::ErrorKind::InvalidInput,
+                            "conflictReportOutput is required for ConflictPolicy.Report"
+                        )
+                        .into()
+                );
+            }
+        };
+
+        Ok(ConflictResolver {
+            policies,
+            headers,
+            report,
+        })
+    }
+
+    pub fn resolve<'v>(
+        &mut self,
+        key_value: &[u8],
+        index: usize,
+        values: &'v FieldValues
+    ) -> Result<&'v [u8], csv::Error> {
+        let policy = self.policies.get(&index).copied().unwrap_or(ConflictPolicy::LastNonEmpty);
+        let value = match policy {
+            ConflictPolicy::LastNonEmpty => values.last.as_deref(),
+            ConflictPolicy::FirstNonEmpty => values.first.as_deref(),
+            ConflictPolicy::PreferLeft => values.left.as_deref().or(values.right.as_deref()),
+            ConflictPolicy::PreferRight => values.right.as_deref().or(values.left.as_deref()),
+            ConflictPolicy::Error => {
+                if let Some((left, right)) = values.conflict() {
+                    return Err(
+                        io::Error
+                            ::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "Conflicting values for column {} at key {}: {:?} vs {:?}",
+                                    self.column(index),
+                                    String::from_utf8_lossy(key_value),
+                                    String::from_utf8_lossy(left),
+                                    String::from_utf8_lossy(right)
+                                )
+                            )
+                            .into()
+                    );
+                }
+                values.last.as_deref()
+            }
+            ConflictPolicy::Report => {
+                if let (Some((left, right)), Some(report)) = (values.conflict(), &mut self.report) {
+                    report.write_record([
+                        key_value,
+                        self.headers.get(index).map_or(b"".as_slice(), |header| header.as_bytes()),
+                        left,
+                        right,
+                    ])?;
+                }
+                values.last.as_deref()
+            }
+        };
+        Ok(value.unwrap_or(b""))
+    }
+
+    pub fn flush(&mut self) -> Result<(), csv::Error> {
+        if let Some(report) = &mut self.report {
+            report.flush()?;
+        }
+        Ok(())
+    }
+
+    fn column(&self, index: usize) -> &str {
+        self.headers.get(index).map_or("", |header| header.as_str())
+    }
+}
@@ -1,20 +1,31 @@
-use std::{ fs::File };
+use csv::ByteRecord;
 
-use csv::{ ByteRecord, Writer };
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io;
 
-use crate::DeduplicateStrategy;
+use crate::{ CrossJoinLimitAction, CrossJoinLimits, DeduplicateStrategy };
 
-pub enum DeduplicateStrategyHandler<'a> {
-    KeepAll(KeepAllStrategyHandler<'a>),
-    FirstOnly(KeepFirstStrategyHandler<'a>),
-    RemoveSimilar(RemoveSimilarStrategyHandler<'a>),
-    Reduce(ReduceStrategyHandler<'a>),
-    CrossJoin(CrossJoinStrategyHandler<'a>),
-    CrossJoinAndRemoveSimilar(CrossJoinStrategyHandler<'a>),
+use super::aggregate::Aggregation;
+use super::audit::{ AuditLog, DropReason };
+use super::conflict::{ ConflictResolver, FieldValues };
+use super::sink::RowSink;
+use super::spill::{ PartitionedRows, SpillGroup };
+
+pub enum DeduplicateStrategyHandler<'a, S> {
+    KeepAll(KeepAllStrategyHandler<'a, S>),
+    FirstOnly(KeepFirstStrategyHandler<'a, S>),
+    LastOnly(KeepLastStrategyHandler<'a, S>),
+    RemoveSimilar(RemoveSimilarStrategyHandler<'a, S>),
+    Reduce(ReduceStrategyHandler<'a, S>),
+    CrossJoin(CrossJoinStrategyHandler<'a, S>),
+    CrossJoinAndRemoveSimilar(CrossJoinStrategyHandler<'a, S>),
+    Custom(CustomStrategyHandler<'a, S>),
+    Distinct(DistinctStrategyHandler<'a, S>),
 }
 
-impl<'a> DeduplicateStrategyHandler<'a> {
-    pub fn add_row(
+impl<'a, S: RowSink> StrategyHandler for DeduplicateStrategyHandler<'a, S> {
+    fn add_row(
         &mut self,
         row: ByteRecord,
         value: Vec<u8>,
@@ -23,36 +34,213 @@ impl<'a> DeduplicateStrategyHandler<'a> {
         match self {
             DeduplicateStrategyHandler::KeepAll(handler) => handler.add_row(row, value, side),
             DeduplicateStrategyHandler::FirstOnly(handler) => handler.add_row(row, value, side),
+            DeduplicateStrategyHandler::LastOnly(handler) => handler.add_row(row, value, side),
             DeduplicateStrategyHandler::RemoveSimilar(handler) => handler.add_row(row, value, side),
             DeduplicateStrategyHandler::Reduce(handler) => handler.add_row(row, value, side),
             DeduplicateStrategyHandler::CrossJoin(handler) => handler.add_row(row, value, side),
             DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) =>
                 handler.add_row(row, value, side),
+            DeduplicateStrategyHandler::Custom(handler) => handler.add_row(row, value, side),
+            DeduplicateStrategyHandler::Distinct(handler) => handler.add_row(row, value, side),
+        }
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        match self {
+            DeduplicateStrategyHandler::KeepAll(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::FirstOnly(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::LastOnly(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::RemoveSimilar(handler) =>
+                handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::Reduce(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::CrossJoin(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) =>
+                handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::Custom(handler) => handler.add_unique_row(row, side),
+            DeduplicateStrategyHandler::Distinct(handler) => handler.add_unique_row(row, side),
         }
     }
 
-    pub fn flush(&mut self) -> Result<(), csv::Error> {
+    fn flush(&mut self) -> Result<(), csv::Error> {
         match self {
             DeduplicateStrategyHandler::KeepAll(handler) => handler.flush(),
             DeduplicateStrategyHandler::FirstOnly(handler) => handler.flush(),
+            DeduplicateStrategyHandler::LastOnly(handler) => handler.flush(),
             DeduplicateStrategyHandler::RemoveSimilar(handler) => handler.flush(),
             DeduplicateStrategyHandler::Reduce(handler) => handler.flush(),
             DeduplicateStrategyHandler::CrossJoin(handler) => handler.flush(),
             DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => handler.flush(),
+            DeduplicateStrategyHandler::Custom(handler) => handler.flush(),
+            DeduplicateStrategyHandler::Distinct(handler) => handler.flush(),
+        }
+    }
+}
+
+impl<'a, S> DeduplicateStrategyHandler<'a, S> {
+    /// Only `Reduce` aggregates, the other strategies ignore the aggregations.
+    pub fn with_aggregations(&mut self, aggregations: Vec<Aggregation>) {
+        if let DeduplicateStrategyHandler::Reduce(handler) = self {
+            handler.aggregations = aggregations;
+        }
+    }
+
+    pub fn with_cross_join_limits(&mut self, limits: CrossJoinLimits) {
+        match self {
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => {
+                handler.limits = Some(limits);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn cross_join_limited_keys(&self) -> Vec<String> {
+        match self {
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) =>
+                handler.limited_keys
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key).to_string())
+                    .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Why each of `cross_join_limited_keys` was limited.
+    pub fn warnings(&self) -> Vec<String> {
+        match self {
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => handler.warnings.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// Bytes of a key group kept in memory before it spills to temporary files.
+    pub fn with_group_memory_limit(&mut self, limit: usize) {
+        match self {
+            DeduplicateStrategyHandler::RemoveSimilar(handler) => {
+                handler.duplicates.set_budget(Some(limit));
+            }
+            DeduplicateStrategyHandler::Reduce(handler) => {
+                handler.group.set_budget(Some(limit));
+            }
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => {
+                // Both sides are read back for a cross join, each gets half.
+                handler.left.set_budget(Some(limit / 2));
+                handler.right.set_budget(Some(limit / 2));
+            }
+            DeduplicateStrategyHandler::Distinct(handler) => {
+                handler.budget = Some(limit);
+            }
+            _ => (),
+        }
+    }
+
+    /// Sets the columns `RemoveSimilar`, `CrossJoinAndRemoveSimilar` and
+    /// `Distinct` compare rows on.
+    pub fn with_similarity(&mut self, similarity: Similarity) {
+        match self {
+            DeduplicateStrategyHandler::RemoveSimilar(handler) => {
+                handler.duplicates.set_order(Some(similarity.clone()));
+                handler.similarity = similarity;
+            }
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) if
+                handler.remove_similar
+            => {
+                handler.left.set_order(Some(similarity.clone()));
+                handler.right.set_order(Some(similarity.clone()));
+                handler.similarity = similarity;
+            }
+            DeduplicateStrategyHandler::Distinct(handler) => {
+                handler.similarity = similarity;
+            }
+            _ => (),
+        }
+    }
+
+    pub fn with_audit_log(&mut self, audit: AuditLog) {
+        match self {
+            DeduplicateStrategyHandler::FirstOnly(handler) => {
+                handler.audit = audit;
+            }
+            DeduplicateStrategyHandler::LastOnly(handler) => {
+                handler.audit = audit;
+            }
+            DeduplicateStrategyHandler::RemoveSimilar(handler) => {
+                handler.audit = audit;
+            }
+            DeduplicateStrategyHandler::Reduce(handler) => {
+                handler.audit = audit;
+            }
+            _ => (),
+        }
+    }
+
+    pub fn with_conflict_resolver(&mut self, conflicts: ConflictResolver) {
+        match self {
+            DeduplicateStrategyHandler::Reduce(handler) => {
+                handler.conflicts = conflicts;
+            }
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => {
+                handler.conflicts = conflicts;
+            }
+            _ => (),
+        }
+    }
+
+    /// Appends to every row the number of input rows it stands for. Not
+    /// supported by `Custom`, whose rows come from the group resolver, nor by
+    /// `Distinct`, which writes rows before their duplicates are known.
+    pub fn with_group_counts(&mut self) {
+        match self {
+            DeduplicateStrategyHandler::KeepAll(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::FirstOnly(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::LastOnly(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::RemoveSimilar(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::Reduce(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::CrossJoin(handler) |
+            DeduplicateStrategyHandler::CrossJoinAndRemoveSimilar(handler) => {
+                handler.group_counts = true;
+            }
+            DeduplicateStrategyHandler::Custom(_) | DeduplicateStrategyHandler::Distinct(_) => (),
+        }
+    }
+
+    /// Sets how `Custom` resolves key groups, with the number of rows
+    /// collected before the resolver is called.
+    pub fn with_group_resolver(&mut self, resolver: GroupResolver, batch_size: usize) {
+        if let DeduplicateStrategyHandler::Custom(handler) = self {
+            handler.resolver = Some(resolver);
+            handler.batch_size = batch_size;
         }
     }
 }
 
 impl DeduplicateStrategy {
-    pub fn create<'a>(
+    pub fn create<S: RowSink>(
         strategy: DeduplicateStrategy,
-        writer: &'a mut Writer<File>
-    ) -> DeduplicateStrategyHandler {
+        writer: &mut S
+    ) -> DeduplicateStrategyHandler<'_, S> {
         match strategy {
             DeduplicateStrategy::KeepAll =>
                 DeduplicateStrategyHandler::KeepAll(KeepAllStrategyHandler::build(writer)),
             DeduplicateStrategy::KeepFirst =>
                 DeduplicateStrategyHandler::FirstOnly(KeepFirstStrategyHandler::build(writer)),
+            DeduplicateStrategy::KeepLast =>
+                DeduplicateStrategyHandler::LastOnly(KeepLastStrategyHandler::build(writer)),
             DeduplicateStrategy::RemoveSimilar =>
                 DeduplicateStrategyHandler::RemoveSimilar(
                     RemoveSimilarStrategyHandler::build(writer)
@@ -67,6 +255,10 @@ impl DeduplicateStrategy {
                 DeduplicateStrategyHandler::CrossJoin(
                     CrossJoinStrategyHandler::build(writer, true)
                 ),
+            DeduplicateStrategy::Custom =>
+                DeduplicateStrategyHandler::Custom(CustomStrategyHandler::build(writer)),
+            DeduplicateStrategy::Distinct =>
+                DeduplicateStrategyHandler::Distinct(DistinctStrategyHandler::build(writer)),
         }
     }
 }
@@ -84,273 +276,1199 @@ pub trait StrategyHandler {
         key_value: Vec<u8>,
         side: Side
     ) -> Result<(), csv::Error>;
+    /// Writes a row whose key never matches any other row, bypassing the
+    /// duplicate group currently being collected.
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error>;
     fn flush(&mut self) -> Result<(), csv::Error>;
 }
 
-pub struct KeepAllStrategyHandler<'a> {
-    writer: &'a mut Writer<File>,
+/// Columns deciding whether two rows of a key group are similar, the whole
+/// record by default.
+#[derive(Clone, Default)]
+pub struct Similarity {
+    columns: Option<Vec<usize>>,
 }
 
-impl<'a> KeepAllStrategyHandler<'a> {
-    pub fn build(writer: &'a mut Writer<File>) -> Self {
+impl Similarity {
+    pub fn on_columns(columns: Vec<usize>) -> Self {
+        Similarity {
+            columns: Some(columns),
+        }
+    }
+
+    pub fn cmp(&self, a: &ByteRecord, b: &ByteRecord) -> Ordering {
+        match &self.columns {
+            None => a.as_slice().cmp(b.as_slice()),
+            Some(columns) =>
+                columns
+                    .iter()
+                    .map(|&i| a.get(i))
+                    .cmp(columns.iter().map(|&i| b.get(i))),
+        }
+    }
+
+    pub fn is_similar(&self, a: &ByteRecord, b: &ByteRecord) -> bool {
+        match &self.columns {
+            None => a == b,
+            Some(_) => self.cmp(a, b).is_eq(),
+        }
+    }
+
+    /// The compared columns of a row as one value, each field prefixed with
+    /// its length so that different splits of the same bytes stay distinct.
+    pub fn value(&self, row: &ByteRecord) -> Vec<u8> {
+        let mut value = vec![];
+        let mut push = |field: &[u8]| {
+            value.extend_from_slice(field.len().to_string().as_bytes());
+            value.push(b':');
+            value.extend_from_slice(field);
+        };
+        match &self.columns {
+            None => row.iter().for_each(&mut push),
+            Some(columns) =>
+                columns
+                    .iter()
+                    .for_each(|&i| push(row.get(i).unwrap_or(b""))),
+        }
+        value
+    }
+
+    fn is_similar_to_previous(&self, previous: &Option<ByteRecord>, record: &ByteRecord) -> bool {
+        previous.as_ref().is_some_and(|previous| self.is_similar(previous, record))
+    }
+}
+
+/// Input rows, per side, that an output row stands for.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct GroupCounts {
+    left: usize,
+    right: usize,
+}
+
+impl GroupCounts {
+    pub const HEADERS: [&'static str; 3] = ["__group_size", "__group_left", "__group_right"];
+
+    fn of(side: &Side) -> Self {
+        let mut counts = GroupCounts::default();
+        counts.add(side);
+        counts
+    }
+
+    fn add(&mut self, side: &Side) {
+        match side {
+            Side::Left => {
+                self.left += 1;
+            }
+            Side::Right => {
+                self.right += 1;
+            }
+        }
+    }
+}
+
+/// Writes an output row, followed by its group counts when they are enabled.
+fn write_row<S: RowSink>(
+    writer: &mut S,
+    row: &ByteRecord,
+    counts: Option<GroupCounts>
+) -> Result<(), csv::Error> {
+    match counts {
+        Some(counts) => {
+            let mut row = row.clone();
+            for count in [counts.left + counts.right, counts.left, counts.right] {
+                row.push_field(count.to_string().as_bytes());
+            }
+            writer.write_byte_record(&row)
+        }
+        None => writer.write_byte_record(row),
+    }
+}
+
+/// Sink that feeds rows into another handler, keyed by the given column,
+/// so that deduplicate stages can be chained.
+pub struct HandlerSink<H> {
+    handler: H,
+    key_index: usize,
+    side: Side,
+}
+
+impl<H: StrategyHandler> HandlerSink<H> {
+    pub fn build(handler: H, key_index: usize, side: Side) -> Self {
+        HandlerSink {
+            handler,
+            key_index,
+            side,
+        }
+    }
+}
+
+impl<H: StrategyHandler> RowSink for HandlerSink<H> {
+    fn write_headers(&mut self, _headers: &[String]) -> Result<(), csv::Error> {
+        Ok(())
+    }
+
+    fn write_byte_record(&mut self, record: &ByteRecord) -> Result<(), csv::Error> {
+        let key_value = record.get(self.key_index).unwrap_or(b"").to_vec();
+        self.handler.add_row(record.clone(), key_value, self.side.clone())
+    }
+
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        self.handler.flush()
+    }
+}
+
+pub struct KeepAllStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    group_counts: bool,
+}
+
+impl<'a, S: RowSink> KeepAllStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
         KeepAllStrategyHandler {
             writer,
+            group_counts: false,
         }
     }
 }
 
-impl<'a> StrategyHandler for KeepAllStrategyHandler<'a> {
-    fn add_row(&mut self, row: ByteRecord, _value: Vec<u8>, _side: Side) -> Result<(), csv::Error> {
-        self.writer.write_record(&row)
+impl<'a, S: RowSink> StrategyHandler for KeepAllStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, _value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        self.add_unique_row(row, side)
+    }
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
     }
+
     fn flush(&mut self) -> Result<(), csv::Error> {
         self.writer.flush()?;
         Ok(())
     }
 }
 
-pub struct KeepFirstStrategyHandler<'a> {
-    writer: &'a mut Writer<File>,
-    last_record: Option<(ByteRecord, Vec<u8>)>,
-    duplicates_counter: u32,
+pub struct KeepFirstStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    /// First row of the current key, written once the key's rows are counted.
+    first_record: Option<(ByteRecord, Vec<u8>, GroupCounts)>,
+    audit: AuditLog,
+    group_counts: bool,
 }
 
-impl<'a> KeepFirstStrategyHandler<'a> {
-    pub fn build(writer: &'a mut Writer<File>) -> Self {
+impl<'a, S: RowSink> KeepFirstStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
         KeepFirstStrategyHandler {
             writer,
-            last_record: None,
-            duplicates_counter: 0,
+            first_record: None,
+            audit: AuditLog::default(),
+            group_counts: false,
         }
     }
-}
 
-impl<'a> StrategyHandler for KeepFirstStrategyHandler<'a> {
-    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, _side: Side) -> Result<(), csv::Error> {
-        let eq = match &self.last_record {
-            Some((_, lr_key_value)) => *lr_key_value == value,
-            None => false,
-        };
+    fn write_first_record(&mut self) -> Result<(), csv::Error> {
+        match self.first_record.take() {
+            Some((first_record, _, counts)) =>
+                write_row(self.writer, &first_record, self.group_counts.then_some(counts)),
+            None => Ok(()),
+        }
+    }
+}
 
-        if self.duplicates_counter == 0 {
-            match &self.last_record {
-                Some((lr, _side)) => self.writer.write_byte_record(lr)?,
-                None => (),
+impl<'a, S: RowSink> StrategyHandler for KeepFirstStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        if let Some((_, first_key_value, counts)) = &mut self.first_record {
+            if *first_key_value == value {
+                counts.add(&side);
+                return self.audit.record(&value, &side, DropReason::Superseded, &row);
             }
         }
+        self.write_first_record()?;
+        self.first_record = Some((row, value, GroupCounts::of(&side)));
+        Ok(())
+    }
 
-        self.duplicates_counter = match eq {
-            true => self.duplicates_counter + 1,
-            false => 0,
-        };
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
+    }
 
-        self.last_record = Some((row, value));
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        self.write_first_record()?;
+        self.audit.flush()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
 
+pub struct KeepLastStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    last_record: Option<(ByteRecord, Vec<u8>, Side)>,
+    counts: GroupCounts,
+    audit: AuditLog,
+    group_counts: bool,
+}
+
+impl<'a, S: RowSink> KeepLastStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
+        KeepLastStrategyHandler {
+            writer,
+            last_record: None,
+            counts: GroupCounts::default(),
+            audit: AuditLog::default(),
+            group_counts: false,
+        }
+    }
+}
+
+impl<'a, S: RowSink> StrategyHandler for KeepLastStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        if let Some((last_record, last_key_value, last_side)) = &self.last_record {
+            if *last_key_value != value {
+                write_row(self.writer, last_record, self.group_counts.then_some(self.counts))?;
+                self.counts = GroupCounts::default();
+            } else {
+                self.audit.record(last_key_value, last_side, DropReason::Superseded, last_record)?;
+            }
+        }
+        self.counts.add(&side);
+        self.last_record = Some((row, value, side));
         Ok(())
     }
 
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
+    }
+
     fn flush(&mut self) -> Result<(), csv::Error> {
-        if self.duplicates_counter == 0 {
-            if let Some((lr, _value)) = &self.last_record {
-                self.writer.write_byte_record(lr)?;
-            }
+        if let Some((last_record, _, _)) = self.last_record.take() {
+            write_row(self.writer, &last_record, self.group_counts.then_some(self.counts))?;
         }
+        self.audit.flush()?;
         self.writer.flush()?;
         Ok(())
     }
 }
-pub struct ReduceStrategyHandler<'a> {
-    writer: &'a mut Writer<File>,
-    group: Option<Vec<(ByteRecord, Vec<u8>)>>,
+
+pub struct ReduceStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    group_key_value: Option<Vec<u8>>,
+    group: SpillGroup,
+    aggregations: Vec<Aggregation>,
+    conflicts: ConflictResolver,
+    audit: AuditLog,
+    group_counts: bool,
 }
 
-impl<'a> ReduceStrategyHandler<'a> {
-    pub fn build(writer: &'a mut Writer<File>) -> Self {
+impl<'a, S: RowSink> ReduceStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
         ReduceStrategyHandler {
             writer,
-            group: None,
+            group_key_value: None,
+            group: SpillGroup::new(None, None),
+            aggregations: vec![],
+            conflicts: ConflictResolver::default(),
+            audit: AuditLog::default(),
+            group_counts: false,
         }
     }
 
     fn flush_group(&mut self) -> Result<(), csv::Error> {
-        if let Some(group) = self.group.take() {
-            let mut reduced: Vec<&[u8]> = group[0].0.into_iter().collect();
-            for (record, _record_side) in group.iter().skip(1) {
-                for (i, field) in record.iter().enumerate() {
-                    if !field.is_empty() {
-                        reduced[i] = field;
-                    }
-                }
+        let key_value = match self.group_key_value.take() {
+            Some(key_value) => key_value,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let mut values: Vec<FieldValues> = vec![];
+        let mut aggregation_values: Vec<Vec<Vec<u8>>> = vec![vec![]; self.aggregations.len()];
+        let folded = self.group.len() > 1;
+        let mut counts = GroupCounts::default();
+        for row in self.group.rows()? {
+            let (record, side) = row?;
+            counts.add(&side);
+            if folded {
+                self.audit.record(&key_value, &side, DropReason::FoldedIntoReduce, &record)?;
+            }
+            if values.len() < record.len() {
+                values.resize(record.len(), FieldValues::default());
             }
+            for (i, field) in record.iter().enumerate() {
+                values[i].push_owned(field, &side);
+            }
+            for (column_values, aggregation) in aggregation_values
+                .iter_mut()
+                .zip(&self.aggregations) {
+                column_values.push(record.get(aggregation.index).unwrap_or(b"").to_vec());
+            }
+        }
+        self.group.clear();
 
-            self.writer.write_byte_record(&ByteRecord::from_iter(reduced))?;
+        let mut reduced: Vec<&[u8]> = Vec::with_capacity(values.len());
+        for (i, field_values) in values.iter().enumerate() {
+            reduced.push(self.conflicts.resolve(&key_value, i, field_values)?);
         }
+
+        let aggregated = self.aggregations
+            .iter()
+            .zip(&aggregation_values)
+            .map(|(aggregation, column_values)| {
+                let column_values: Vec<&[u8]> = column_values
+                    .iter()
+                    .map(|value| value.as_slice())
+                    .collect();
+                Ok((aggregation.index, aggregation.apply(&column_values)?))
+            })
+            .collect::<Result<Vec<_>, csv::Error>>()?;
+        for (index, value) in &aggregated {
+            if *index < reduced.len() {
+                reduced[*index] = value;
+            }
+        }
+
+        write_row(self.writer, &ByteRecord::from_iter(reduced), self.group_counts.then_some(counts))?;
         Ok(())
     }
 }
 
-impl<'a> StrategyHandler for ReduceStrategyHandler<'a> {
-    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, _side: Side) -> Result<(), csv::Error> {
-        if let Some(group) = &mut self.group {
-            let (_, group_key) = &group[0];
-
-            if &value == group_key {
-                group.push((row, value));
-                return Ok(());
-            }
+impl<'a, S: RowSink> StrategyHandler for ReduceStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        if self.group_key_value.as_ref() != Some(&value) {
             self.flush_group()?;
+            self.group_key_value = Some(value);
         }
-        self.group = Some(vec![(row, value)]);
-        Ok(())
+        self.group.push(row, side)
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
     }
 
     fn flush(&mut self) -> Result<(), csv::Error> {
         self.flush_group()?;
+        self.conflicts.flush()?;
+        self.audit.flush()?;
         self.writer.flush()?;
         Ok(())
     }
 }
 
-pub struct CrossJoinStrategyHandler<'a> {
-    writer: &'a mut Writer<File>,
+pub struct CrossJoinStrategyHandler<'a, S> {
+    writer: &'a mut S,
     last_row_key_value: Option<Vec<u8>>,
-    duplicates: Vec<(ByteRecord, Vec<u8>, Side)>,
+    left: SpillGroup,
+    right: SpillGroup,
     remove_similar: bool,
+    similarity: Similarity,
+    conflicts: ConflictResolver,
+    limits: Option<CrossJoinLimits>,
+    limited_keys: Vec<Vec<u8>>,
+    warnings: Vec<String>,
+    /// The current key hit a limit, its remaining rows are ignored.
+    group_limited: bool,
+    group_counts: bool,
 }
 
-impl<'a> CrossJoinStrategyHandler<'a> {
-    pub fn build(writer: &'a mut Writer<File>, remove_similar: bool) -> Self {
+impl<'a, S: RowSink> CrossJoinStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S, remove_similar: bool) -> Self {
         CrossJoinStrategyHandler {
             writer,
             last_row_key_value: None,
-            duplicates: vec![],
+            left: SpillGroup::new(None, remove_similar.then(Similarity::default)),
+            right: SpillGroup::new(None, remove_similar.then(Similarity::default)),
             remove_similar,
+            similarity: Similarity::default(),
+            conflicts: ConflictResolver::default(),
+            limits: None,
+            limited_keys: vec![],
+            warnings: vec![],
+            group_limited: false,
+            group_counts: false,
         }
     }
 
-    fn flush_duplicates(&mut self) -> Result<(), csv::Error> {
-        if self.remove_similar {
-            self.duplicates.sort_by(|a, b| a.0.as_slice().cmp(&b.0.as_slice()));
-            self.duplicates.dedup();
+    fn limit_action(&self) -> CrossJoinLimitAction {
+        self.limits
+            .as_ref()
+            .and_then(|limits| limits.action)
+            .unwrap_or(CrossJoinLimitAction::Fail)
+    }
+
+    /// Applies the limit action to the current key. Returns whether the rows
+    /// collected so far should still be written.
+    fn limit_group(&mut self, key_value: &[u8], reason: String) -> Result<bool, csv::Error> {
+        let message = format!(
+            "Cross join limit exceeded for key {}: {}",
+            String::from_utf8_lossy(key_value),
+            reason
+        );
+        let action = self.limit_action();
+        if let CrossJoinLimitAction::Fail = action {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+        if !self.group_limited {
+            self.group_limited = true;
+            self.limited_keys.push(key_value.to_vec());
+            self.warnings.push(message);
         }
+        Ok(matches!(action, CrossJoinLimitAction::Truncate))
+    }
 
-        let left_records: Vec<ByteRecord> = self.duplicates
-            .iter()
-            .filter(|x| x.2 == Side::Left)
-            .map(|x| x.0.clone())
-            .collect();
+    /// Rows of one side, not counting similar ones when they are removed.
+    fn count_rows(
+        group: &mut SpillGroup,
+        similarity: Option<&Similarity>
+    ) -> Result<usize, csv::Error> {
+        let Some(similarity) = similarity else {
+            return Ok(group.len());
+        };
+        let mut count = 0;
+        let mut previous: Option<ByteRecord> = None;
+        for row in group.rows()? {
+            let (record, _) = row?;
+            if !similarity.is_similar_to_previous(&previous, &record) {
+                count += 1;
+                previous = Some(record);
+            }
+        }
+        Ok(count)
+    }
 
-        let right_records: Vec<ByteRecord> = self.duplicates
-            .iter()
-            .filter(|x| x.2 == Side::Right)
-            .map(|x| x.0.clone())
-            .collect();
+    fn flush_duplicates(&mut self) -> Result<(), csv::Error> {
+        let key_value = self.last_row_key_value.clone().unwrap_or_default();
+        let similarity = self.remove_similar.then(|| self.similarity.clone());
+        let similarity = similarity.as_ref();
+        // Every row written for the key stands for the whole group.
+        let counts = self.group_counts.then_some(GroupCounts {
+            left: self.left.len(),
+            right: self.right.len(),
+        });
 
-        if right_records.len() > 0 && left_records.len() > 0 {
-            for left_record in left_records {
-                for right_record in &right_records {
-                    let mut computed: Vec<&[u8]> = left_record.iter().collect();
-                    for (i, field) in right_record.iter().enumerate() {
-                        if !field.is_empty() {
-                            computed[i] = field;
-                        }
-                    }
-                    self.writer.write_byte_record(&ByteRecord::from_iter(computed))?;
+        if !self.left.is_empty() && !self.right.is_empty() {
+            let mut remaining = usize::MAX;
+            if let Some(max_product) = self.limits.as_ref().and_then(|limits| limits.max_product) {
+                let max_product = max_product as usize;
+                let product =
+                    Self::count_rows(&mut self.left, similarity)? *
+                    Self::count_rows(&mut self.right, similarity)?;
+                if product > max_product {
+                    let reason = format!("{} combinations exceed maxProduct {}", product, max_product);
+                    remaining = match self.limit_group(&key_value, reason)? {
+                        true => max_product,
+                        false => 0,
+                    };
                 }
             }
-        } else if right_records.len() > 0 {
-            for right_record in right_records {
-                self.writer.write_byte_record(&right_record)?;
+
+            // Opened once and rewound for every left row.
+            let mut right_rows = self.right.rows()?;
+            let mut previous_left: Option<ByteRecord> = None;
+            for left_row in self.left.rows()? {
+                let (left_record, _) = left_row?;
+                if remaining == 0 {
+                    break;
+                }
+                if similarity.is_some_and(|s| s.is_similar_to_previous(&previous_left, &left_record)) {
+                    continue;
+                }
+
+                right_rows.rewind()?;
+                let mut previous_right: Option<ByteRecord> = None;
+                for right_row in right_rows.by_ref() {
+                    let (right_record, _) = right_row?;
+                    if remaining == 0 {
+                        break;
+                    }
+                    if
+                        similarity.is_some_and(|s| s.is_similar_to_previous(&previous_right, &right_record))
+                    {
+                        continue;
+                    }
+                    remaining -= 1;
+
+                    let mut values = vec![FieldValues::default(); left_record.len()];
+                    for (i, left_field) in left_record.iter().enumerate() {
+                        values[i].push(left_field, &Side::Left);
+                        values[i].push(right_record.get(i).unwrap_or(b""), &Side::Right);
+                    }
+                    let mut computed: Vec<&[u8]> = Vec::with_capacity(values.len());
+                    for (i, field_values) in values.iter().enumerate() {
+                        computed.push(self.conflicts.resolve(&key_value, i, field_values)?);
+                    }
+                    write_row(self.writer, &ByteRecord::from_iter(computed), counts)?;
+
+                    previous_right = Some(right_record);
+                }
+                previous_left = Some(left_record);
             }
         } else {
-            for left_record in left_records {
-                self.writer.write_byte_record(&left_record)?;
+            let group = if self.right.is_empty() { &mut self.left } else { &mut self.right };
+            let mut previous: Option<ByteRecord> = None;
+            for row in group.rows()? {
+                let (record, _) = row?;
+                if similarity.is_some_and(|s| s.is_similar_to_previous(&previous, &record)) {
+                    continue;
+                }
+                write_row(self.writer, &record, counts)?;
+                previous = Some(record);
             }
         }
 
-        self.duplicates = vec![];
+        self.left.clear();
+        self.right.clear();
+        self.group_limited = false;
         Ok(())
     }
 }
 
-impl<'a> StrategyHandler for CrossJoinStrategyHandler<'a> {
+impl<'a, S: RowSink> StrategyHandler for CrossJoinStrategyHandler<'a, S> {
     fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
-        let is_equal;
-        if let Some(last_row_key_value) = &self.last_row_key_value {
-            is_equal = last_row_key_value == &value;
-            if !is_equal && self.duplicates.len() > 0 {
-                self.flush_duplicates()?;
-            }
-            if (is_equal && self.duplicates.len() > 0) || (!is_equal && self.duplicates.len() == 0) {
-                self.duplicates.push((row.clone(), value.clone(), side.clone()));
-            } else {
-                self.flush_duplicates()?;
+        if self.last_row_key_value.as_ref() != Some(&value) {
+            self.flush_duplicates()?;
+            self.last_row_key_value = Some(value.clone());
+        }
+        if self.group_limited {
+            return Ok(());
+        }
+        if let Some(max_group_size) = self.limits.as_ref().and_then(|limits| limits.max_group_size) {
+            if self.left.len() + self.right.len() >= (max_group_size as usize) {
+                let reason = format!("more rows than maxGroupSize {}", max_group_size);
+                if !self.limit_group(&value, reason)? {
+                    self.left.clear();
+                    self.right.clear();
+                }
+                return Ok(());
             }
-        } else {
-            self.duplicates.push((row.clone(), value.clone(), side.clone()));
         }
-        self.last_row_key_value = Some(value);
-        Ok(())
+        match side {
+            Side::Left => self.left.push(row, side),
+            Side::Right => self.right.push(row, side),
+        }
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
     }
 
     fn flush(&mut self) -> Result<(), csv::Error> {
         self.flush_duplicates()?;
+        self.conflicts.flush()?;
         self.writer.flush()?;
         Ok(())
     }
 }
 
-pub struct RemoveSimilarStrategyHandler<'a> {
-    writer: &'a mut Writer<File>,
-    last_row: Option<(ByteRecord, Vec<u8>)>,
-    duplicates: Vec<ByteRecord>,
+pub struct RemoveSimilarStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    last_row_key_value: Option<Vec<u8>>,
+    duplicates: SpillGroup,
+    similarity: Similarity,
+    audit: AuditLog,
+    group_counts: bool,
 }
 
-impl<'a> RemoveSimilarStrategyHandler<'a> {
-    pub fn build(writer: &'a mut Writer<File>) -> Self {
+impl<'a, S: RowSink> RemoveSimilarStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
         RemoveSimilarStrategyHandler {
             writer,
-            last_row: None,
-            duplicates: vec![],
+            last_row_key_value: None,
+            duplicates: SpillGroup::new(None, Some(Similarity::default())),
+            similarity: Similarity::default(),
+            audit: AuditLog::default(),
+            group_counts: false,
         }
     }
 
     fn flush_duplicates(&mut self) -> Result<(), csv::Error> {
-        self.duplicates.sort_by(|a, b| a.as_slice().cmp(&b.as_slice()));
-        self.duplicates.dedup();
+        let key_value = self.last_row_key_value.clone().unwrap_or_default();
+        // The kept row is written once all rows similar to it are counted.
+        let mut kept: Option<(ByteRecord, GroupCounts)> = None;
+        for row in self.duplicates.rows()? {
+            let (record, side) = row?;
+            match &mut kept {
+                Some((previous, counts)) if self.similarity.is_similar(previous, &record) => {
+                    counts.add(&side);
+                    let reason = match *previous == record {
+                        true => DropReason::ExactDuplicate,
+                        false => DropReason::Similar,
+                    };
+                    self.audit.record(&key_value, &side, reason, &record)?;
+                }
+                _ => {
+                    if let Some((previous, counts)) = kept.take() {
+                        write_row(self.writer, &previous, self.group_counts.then_some(counts))?;
+                    }
+                    kept = Some((record, GroupCounts::of(&side)));
+                }
+            }
+        }
+        if let Some((previous, counts)) = kept {
+            write_row(self.writer, &previous, self.group_counts.then_some(counts))?;
+        }
+
+        self.duplicates.clear();
+        Ok(())
+    }
+}
 
-        for record in &self.duplicates {
-            self.writer.write_byte_record(record)?;
+impl<'a, S: RowSink> StrategyHandler for RemoveSimilarStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        if self.last_row_key_value.as_ref() != Some(&value) {
+            self.flush_duplicates()?;
+            self.last_row_key_value = Some(value);
         }
+        self.duplicates.push(row, side)
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        write_row(self.writer, &row, self.group_counts.then(|| GroupCounts::of(&side)))
+    }
 
-        self.duplicates = vec![];
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        self.flush_duplicates()?;
+        self.audit.flush()?;
+        self.writer.flush()?;
         Ok(())
     }
 }
 
-impl<'a> StrategyHandler for RemoveSimilarStrategyHandler<'a> {
-    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, _side: Side) -> Result<(), csv::Error> {
-        let is_equal;
-        if let Some((_, last_row_value)) = &self.last_row {
-            is_equal = last_row_value == &value;
-            if !is_equal && self.duplicates.len() > 0 {
-                self.flush_duplicates()?;
-            }
-            if (is_equal && self.duplicates.len() > 0) || (!is_equal && self.duplicates.len() == 0) {
-                self.duplicates.push(row.clone());
-            } else {
-                self.flush_duplicates()?;
-            }
-        } else {
-            self.duplicates.push(row.clone());
+/// Rows sharing a key, as handed to a `Custom` group resolver.
+#[derive(Default)]
+pub struct KeyGroup {
+    pub key_value: Vec<u8>,
+    pub left: Vec<ByteRecord>,
+    pub right: Vec<ByteRecord>,
+}
+
+/// Turns a batch of key groups into the rows to write for them.
+pub type GroupResolver = Box<dyn FnMut(Vec<KeyGroup>) -> Result<Vec<ByteRecord>, csv::Error>>;
+
+pub struct CustomStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    resolver: Option<GroupResolver>,
+    group: Option<KeyGroup>,
+    batch: Vec<KeyGroup>,
+    batch_rows: usize,
+    batch_size: usize,
+}
+
+impl<'a, S: RowSink> CustomStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
+        CustomStrategyHandler {
+            writer,
+            resolver: None,
+            group: None,
+            batch: vec![],
+            batch_rows: 0,
+            batch_size: 1000,
+        }
+    }
+
+    fn close_group(&mut self) -> Result<(), csv::Error> {
+        if let Some(group) = self.group.take() {
+            self.batch_rows += group.left.len() + group.right.len();
+            self.batch.push(group);
+        }
+        if self.batch_rows >= self.batch_size {
+            self.flush_batch()?;
         }
-        self.last_row = Some((row, value));
         Ok(())
     }
 
+    fn flush_batch(&mut self) -> Result<(), csv::Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let resolver = self.resolver.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "DeduplicateStrategy.Custom needs a groupCallback")
+        })?;
+        for row in resolver(std::mem::take(&mut self.batch))? {
+            self.writer.write_byte_record(&row)?;
+        }
+        self.batch_rows = 0;
+        Ok(())
+    }
+}
+
+impl<'a, S: RowSink> StrategyHandler for CustomStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        if self.group.as_ref().is_some_and(|group| group.key_value != value) {
+            self.close_group()?;
+        }
+        let group = self.group.get_or_insert_with(|| KeyGroup {
+            key_value: value,
+            ..KeyGroup::default()
+        });
+        match side {
+            Side::Left => group.left.push(row),
+            Side::Right => group.right.push(row),
+        }
+        Ok(())
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, _side: Side) -> Result<(), csv::Error> {
+        self.writer.write_byte_record(&row)
+    }
+
     fn flush(&mut self) -> Result<(), csv::Error> {
-        self.flush_duplicates()?;
+        self.close_group()?;
+        self.flush_batch()?;
         self.writer.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Keeps the first row of every value of the similarity columns, wherever
+/// it occurs, so the input does not have to be sorted. Seen values are kept
+/// in a hash set; past the budget, the remaining rows are partitioned to
+/// temporary files and deduplicated on flush.
+pub struct DistinctStrategyHandler<'a, S> {
+    writer: &'a mut S,
+    similarity: Similarity,
+    seen: HashSet<Vec<u8>>,
+    size: usize,
+    budget: Option<usize>,
+    spilled: Option<PartitionedRows>,
+}
+
+impl<'a, S: RowSink> DistinctStrategyHandler<'a, S> {
+    pub fn build(writer: &'a mut S) -> Self {
+        DistinctStrategyHandler {
+            writer,
+            similarity: Similarity::default(),
+            seen: HashSet::new(),
+            size: 0,
+            budget: None,
+            spilled: None,
+        }
+    }
+}
+
+impl<'a, S: RowSink> StrategyHandler for DistinctStrategyHandler<'a, S> {
+    fn add_row(&mut self, row: ByteRecord, _value: Vec<u8>, side: Side) -> Result<(), csv::Error> {
+        let value = self.similarity.value(&row);
+        if let Some(spilled) = &mut self.spilled {
+            return spilled.push(&value, &row, &side);
+        }
+        if self.seen.contains(&value) {
+            return Ok(());
+        }
+        self.writer.write_byte_record(&row)?;
+        self.size += value.len() + std::mem::size_of::<Vec<u8>>();
+        self.seen.insert(value);
+
+        if self.budget.is_some_and(|budget| self.size > budget) {
+            self.spilled = Some(PartitionedRows::create(self.seen.drain())?);
+            self.size = 0;
+        }
+        Ok(())
+    }
+
+    fn add_unique_row(&mut self, row: ByteRecord, _side: Side) -> Result<(), csv::Error> {
+        self.writer.write_byte_record(&row)
+    }
+
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        if let Some(spilled) = self.spilled.take() {
+            spilled.write_distinct(|row, _| self.writer.write_byte_record(&row))?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use crate::{ ConflictPolicy, CrossJoinLimitAction, CrossJoinLimits };
+
+    use super::*;
+
+    fn rows() -> Vec<(Side, Vec<&'static str>)> {
+        vec![
+            (Side::Left, vec!["1", "a", ""]),
+            (Side::Right, vec!["1", "", "x"]),
+            (Side::Left, vec!["1", "a", ""]),
+            (Side::Left, vec!["2", "b", ""]),
+            (Side::Right, vec!["3", "", "y"]),
+            (Side::Right, vec!["3", "", "z"])
+        ]
+    }
+
+    fn feed(handler: &mut impl StrategyHandler, rows: Vec<(Side, Vec<&str>)>) {
+        for (side, fields) in rows {
+            let key_value = fields[0].as_bytes().to_vec();
+            handler.add_row(ByteRecord::from(fields), key_value, side).unwrap();
+        }
+        handler.flush().unwrap();
+    }
+
+    fn to_strings(output: Vec<ByteRecord>) -> Vec<Vec<String>> {
+        output
+            .iter()
+            .map(|record|
+                record
+                    .iter()
+                    .map(|field| String::from_utf8(field.to_vec()).unwrap())
+                    .collect()
+            )
+            .collect()
+    }
+
+    fn run(strategy: DeduplicateStrategy) -> Vec<Vec<String>> {
+        let mut output: Vec<ByteRecord> = vec![];
+        feed(&mut DeduplicateStrategy::create(strategy, &mut output), rows());
+        to_strings(output)
+    }
+
+    #[test]
+    fn test_keep_all() {
+        let expected: Vec<Vec<&str>> = rows()
+            .into_iter()
+            .map(|(_, fields)| fields)
+            .collect();
+        assert_eq!(run(DeduplicateStrategy::KeepAll), expected);
+    }
+
+    #[test]
+    fn test_keep_first() {
+        assert_eq!(run(DeduplicateStrategy::KeepFirst), [
+            ["1", "a", ""],
+            ["2", "b", ""],
+            ["3", "", "y"],
+        ]);
+    }
+
+    #[test]
+    fn test_keep_first_writes_repeated_key() {
+        let mut output: Vec<ByteRecord> = vec![];
+        feed(
+            &mut DeduplicateStrategy::create(DeduplicateStrategy::KeepFirst, &mut output),
+            vec![(Side::Left, vec!["1", "a", ""]), (Side::Left, vec!["1", "b", ""])]
+        );
+        assert_eq!(to_strings(output), [["1", "a", ""]]);
+    }
+
+    #[test]
+    fn test_keep_last() {
+        assert_eq!(run(DeduplicateStrategy::KeepLast), [
+            ["1", "a", ""],
+            ["2", "b", ""],
+            ["3", "", "z"],
+        ]);
+    }
+
+    #[test]
+    fn test_remove_similar() {
+        assert_eq!(run(DeduplicateStrategy::RemoveSimilar), [
+            ["1", "a", ""],
+            ["1", "", "x"],
+            ["2", "b", ""],
+            ["3", "", "y"],
+            ["3", "", "z"],
+        ]);
+    }
+
+    #[test]
+    fn test_remove_similar_on_columns() {
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::RemoveSimilar, &mut output);
+        handler.with_similarity(Similarity::on_columns(vec![0, 1]));
+        feed(&mut handler, vec![
+            (Side::Left, vec!["1", "b", "10:00"]),
+            (Side::Left, vec!["1", "a", "11:00"]),
+            (Side::Left, vec!["1", "b", "12:00"])
+        ]);
+
+        assert_eq!(to_strings(output), [
+            ["1", "a", "11:00"],
+            ["1", "b", "10:00"],
+        ]);
+    }
+
+    #[test]
+    fn test_group_counts() {
+        let run_counted = |strategy| {
+            let mut output: Vec<ByteRecord> = vec![];
+            let mut handler = DeduplicateStrategy::create(strategy, &mut output);
+            handler.with_group_counts();
+            feed(&mut handler, rows());
+            to_strings(output)
+        };
+
+        assert_eq!(run_counted(DeduplicateStrategy::KeepFirst), [
+            ["1", "a", "", "3", "2", "1"],
+            ["2", "b", "", "1", "1", "0"],
+            ["3", "", "y", "2", "0", "2"],
+        ]);
+        assert_eq!(run_counted(DeduplicateStrategy::KeepLast), [
+            ["1", "a", "", "3", "2", "1"],
+            ["2", "b", "", "1", "1", "0"],
+            ["3", "", "z", "2", "0", "2"],
+        ]);
+        assert_eq!(run_counted(DeduplicateStrategy::RemoveSimilar), [
+            ["1", "a", "", "2", "2", "0"],
+            ["1", "", "x", "1", "0", "1"],
+            ["2", "b", "", "1", "1", "0"],
+            ["3", "", "y", "1", "0", "1"],
+            ["3", "", "z", "1", "0", "1"],
+        ]);
+        assert_eq!(run_counted(DeduplicateStrategy::Reduce), [
+            ["1", "a", "x", "3", "2", "1"],
+            ["2", "b", "", "1", "1", "0"],
+            ["3", "", "z", "2", "0", "2"],
+        ]);
+    }
+
+    #[test]
+    fn test_reduce() {
+        assert_eq!(run(DeduplicateStrategy::Reduce), [
+            ["1", "a", "x"],
+            ["2", "b", ""],
+            ["3", "", "z"],
+        ]);
+    }
+
+    #[test]
+    fn test_reduce_conflict_policies() {
+        let policies = HashMap::from([
+            (1, ConflictPolicy::PreferLeft),
+            (2, ConflictPolicy::FirstNonEmpty),
+        ]);
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::Reduce, &mut output);
+        handler.with_conflict_resolver(ConflictResolver::create(policies, vec![], None).unwrap());
+        feed(&mut handler, vec![
+            (Side::Right, vec!["1", "r", "x"]),
+            (Side::Left, vec!["1", "l", "y"]),
+            (Side::Right, vec!["1", "", "z"])
+        ]);
+
+        assert_eq!(to_strings(output), [["1", "l", "x"]]);
+    }
+
+    #[test]
+    fn test_cross_join_conflict_error() {
+        let policies = HashMap::from([(1, ConflictPolicy::Error)]);
+        let headers = vec!["key".to_string(), "name".to_string()];
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::CrossJoin, &mut output);
+        handler.with_conflict_resolver(ConflictResolver::create(policies, headers, None).unwrap());
+        handler.add_row(ByteRecord::from(vec!["1", "a"]), b"1".to_vec(), Side::Left).unwrap();
+        handler.add_row(ByteRecord::from(vec!["1", "b"]), b"1".to_vec(), Side::Right).unwrap();
+
+        assert_eq!(
+            handler.flush().unwrap_err().to_string(),
+            "Conflicting values for column name at key 1: \"a\" vs \"b\""
+        );
+    }
+
+    #[test]
+    fn test_cross_join() {
+        assert_eq!(run(DeduplicateStrategy::CrossJoin), [
+            ["1", "a", "x"],
+            ["1", "a", "x"],
+            ["2", "b", ""],
+            ["3", "", "y"],
+            ["3", "", "z"],
+        ]);
+    }
+
+    fn run_cross_join_limited(limits: CrossJoinLimits) -> (Vec<Vec<String>>, Vec<String>, Vec<String>) {
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::CrossJoin, &mut output);
+        handler.with_cross_join_limits(limits);
+        feed(&mut handler, vec![
+            (Side::Left, vec!["1", "a", ""]),
+            (Side::Left, vec!["1", "b", ""]),
+            (Side::Right, vec!["1", "", "x"]),
+            (Side::Right, vec!["1", "", "y"]),
+            (Side::Left, vec!["2", "c", ""])
+        ]);
+        let limited_keys = handler.cross_join_limited_keys();
+        let warnings = handler.warnings();
+        drop(handler);
+        (to_strings(output), limited_keys, warnings)
+    }
+
+    #[test]
+    fn test_cross_join_limits() {
+        let (output, limited_keys, warnings) = run_cross_join_limited(CrossJoinLimits {
+            max_group_size: None,
+            max_product: Some(3),
+            action: Some(CrossJoinLimitAction::Truncate),
+        });
+        assert_eq!(output, [
+            ["1", "a", "x"],
+            ["1", "a", "y"],
+            ["1", "b", "x"],
+            ["2", "c", ""],
+        ]);
+        assert_eq!(limited_keys, ["1"]);
+        assert_eq!(warnings, ["Cross join limit exceeded for key 1: 4 combinations exceed maxProduct 3"]);
+
+        let (output, limited_keys, warnings) = run_cross_join_limited(CrossJoinLimits {
+            max_group_size: Some(3),
+            max_product: None,
+            action: Some(CrossJoinLimitAction::Skip),
+        });
+        assert_eq!(output, [["2", "c", ""]]);
+        assert_eq!(limited_keys, ["1"]);
+        // Reported once, not for every row past the limit.
+        assert_eq!(warnings, ["Cross join limit exceeded for key 1: more rows than maxGroupSize 3"]);
+
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::CrossJoin, &mut output);
+        handler.with_cross_join_limits(CrossJoinLimits {
+            max_group_size: Some(1),
+            max_product: None,
+            action: None,
+        });
+        handler.add_row(ByteRecord::from(vec!["1", "a"]), b"1".to_vec(), Side::Left).unwrap();
+        let err = handler
+            .add_row(ByteRecord::from(vec!["1", "b"]), b"1".to_vec(), Side::Left)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cross join limit exceeded for key 1: more rows than maxGroupSize 1");
+    }
+
+    #[test]
+    fn test_cross_join_and_remove_similar() {
+        assert_eq!(run(DeduplicateStrategy::CrossJoinAndRemoveSimilar), [
+            ["1", "a", "x"],
+            ["2", "b", ""],
+            ["3", "", "y"],
+            ["3", "", "z"],
+        ]);
+    }
+
+    #[test]
+    fn test_spilled_groups_match_in_memory() {
+        for strategy in [
+            DeduplicateStrategy::RemoveSimilar,
+            DeduplicateStrategy::Reduce,
+            DeduplicateStrategy::CrossJoin,
+            DeduplicateStrategy::CrossJoinAndRemoveSimilar,
+        ] {
+            let mut output: Vec<ByteRecord> = vec![];
+            let mut handler = DeduplicateStrategy::create(strategy, &mut output);
+            handler.with_group_memory_limit(1);
+            feed(&mut handler, rows());
+            drop(handler);
+
+            assert_eq!(to_strings(output), run(strategy));
+        }
+    }
+
+    #[test]
+    fn test_unique_row_bypasses_group() {
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::Reduce, &mut output);
+        handler.add_row(ByteRecord::from(vec!["1", "a", ""]), b"1".to_vec(), Side::Left).unwrap();
+        handler.add_unique_row(ByteRecord::from(vec!["", "u", ""]), Side::Left).unwrap();
+        handler.add_row(ByteRecord::from(vec!["1", "", "x"]), b"1".to_vec(), Side::Right).unwrap();
+        handler.flush().unwrap();
+
+        assert_eq!(to_strings(output), [
+            ["", "u", ""],
+            ["1", "a", "x"],
+        ]);
+    }
+
+    #[test]
+    fn test_chained_handlers() {
+        let mut output: Vec<ByteRecord> = vec![];
+        let keep_first = DeduplicateStrategy::create(DeduplicateStrategy::KeepFirst, &mut output);
+        let mut sink = HandlerSink::build(keep_first, 0, Side::Left);
+        feed(&mut DeduplicateStrategy::create(DeduplicateStrategy::RemoveSimilar, &mut sink), rows());
+        drop(sink);
+
+        assert_eq!(to_strings(output), [
+            ["1", "a", ""],
+            ["2", "b", ""],
+            ["3", "", "y"],
+        ]);
+    }
+
+    #[test]
+    fn test_custom_resolves_groups_in_batches() {
+        type Batch = Vec<(String, usize, usize)>;
+        let batches: Rc<RefCell<Vec<Batch>>> = Rc::default();
+        let resolver_batches = batches.clone();
+        let resolver: GroupResolver = Box::new(move |groups: Vec<KeyGroup>| {
+            resolver_batches.borrow_mut().push(
+                groups
+                    .iter()
+                    .map(|group| {
+                        (
+                            String::from_utf8(group.key_value.clone()).unwrap(),
+                            group.left.len(),
+                            group.right.len(),
+                        )
+                    })
+                    .collect()
+            );
+            // Keeps the last row of each group, right rows winning.
+            Ok(
+                groups
+                    .into_iter()
+                    .filter_map(|group| group.right.last().or(group.left.last()).cloned())
+                    .collect()
+            )
+        });
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::Custom, &mut output);
+        handler.with_group_resolver(resolver, 3);
+        feed(&mut handler, rows());
+
+        assert_eq!(*batches.borrow(), [
+            vec![("1".to_string(), 2, 1)],
+            vec![("2".to_string(), 1, 0), ("3".to_string(), 0, 2)],
+        ]);
+        assert_eq!(to_strings(output), [
+            ["1", "", "x"],
+            ["2", "b", ""],
+            ["3", "", "z"],
+        ]);
+    }
+
+    #[test]
+    fn test_custom_without_resolver() {
+        let mut output: Vec<ByteRecord> = vec![];
+        let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::Custom, &mut output);
+        handler.add_row(ByteRecord::from(vec!["1"]), b"1".to_vec(), Side::Left).unwrap();
+        let err = handler.flush().unwrap_err();
+        assert_eq!(err.to_string(), "DeduplicateStrategy.Custom needs a groupCallback");
+    }
+
+    #[test]
+    fn test_distinct_matches_with_and_without_budget() {
+        let unsorted = || {
+            vec![
+                (Side::Left, vec!["3", "", "y"]),
+                (Side::Left, vec!["1", "a", ""]),
+                (Side::Right, vec!["3", "", "y"]),
+                (Side::Left, vec!["2", "b", ""]),
+                (Side::Right, vec!["1", "", "x"]),
+                (Side::Left, vec!["1", "a", ""])
+            ]
+        };
+        let run_distinct = |budget: Option<usize>, similarity: Similarity| {
+            let mut output: Vec<ByteRecord> = vec![];
+            let mut handler = DeduplicateStrategy::create(DeduplicateStrategy::Distinct, &mut output);
+            if let Some(budget) = budget {
+                handler.with_group_memory_limit(budget);
+            }
+            handler.with_similarity(similarity);
+            feed(&mut handler, unsorted());
+            to_strings(output)
+        };
+
+        let expected = [["3", "", "y"], ["1", "a", ""], ["2", "b", ""], ["1", "", "x"]];
+        assert_eq!(run_distinct(None, Similarity::default()), expected);
+        assert_eq!(run_distinct(Some(1), Similarity::default()), expected);
+
+        let by_key = [["3", "", "y"], ["1", "a", ""], ["2", "b", ""]];
+        assert_eq!(run_distinct(None, Similarity::on_columns(vec![0])), by_key);
+        assert_eq!(run_distinct(Some(1), Similarity::on_columns(vec![0])), by_key);
+    }
+}
@@ -0,0 +1,338 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::mpsc::{ self, Receiver, Sender };
+use std::sync::{ Arc, Mutex };
+
+use napi::bindgen_prelude::{ Buffer, Either3 };
+use napi::threadsafe_function::{
+    ErrorStrategy,
+    ThreadSafeCallContext,
+    ThreadsafeFunction,
+    ThreadsafeFunctionCallMode,
+};
+use napi::{
+    CallContext,
+    Env,
+    JsBuffer,
+    JsFunction,
+    JsNumber,
+    JsObject,
+    JsUndefined,
+    JsUnknown,
+    NapiRaw,
+    NapiValue,
+    ValueType,
+};
+use napi_derive::js_function;
+
+/// Chunks queued from a stream before it is paused. It is resumed once the
+/// merge has read half of them.
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+pub enum StreamChunk {
+    Data(Vec<u8>),
+    End,
+    Error(String),
+}
+
+/// Source of a merge side: a file path, an in-memory buffer or the chunks
+/// of a Node `Readable` forwarded from the main thread.
+pub enum MergeInput {
+    Path(String),
+    Buffer(Buffer),
+    Stream(ChannelReader),
+}
+
+impl MergeInput {
+    pub fn from_js(env: &Env, input: Either3<String, Buffer, JsObject>) -> napi::Result<Self> {
+        Ok(Self::subscribe(env, input)?.0)
+    }
+
+    /// Resolves both sides of a merge. When the right input fails, the left
+    /// stream, if any, is unsubscribed again.
+    pub fn pair_from_js(
+        env: &Env,
+        left: Either3<String, Buffer, JsObject>,
+        right: Either3<String, Buffer, JsObject>
+    ) -> napi::Result<(Self, Self)> {
+        let (left, subscription) = Self::subscribe(env, left)?;
+        match Self::from_js(env, right) {
+            Ok(right) => Ok((left, right)),
+            Err(err) => {
+                if let Some(subscription) = subscription {
+                    subscription.unsubscribe(env)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn subscribe(
+        env: &Env,
+        input: Either3<String, Buffer, JsObject>
+    ) -> napi::Result<(Self, Option<Subscription>)> {
+        match input {
+            Either3::A(path) => Ok((MergeInput::Path(path), None)),
+            Either3::B(buffer) => Ok((MergeInput::Buffer(buffer), None)),
+            Either3::C(stream) => {
+                let (reader, subscription) = subscribe_stream(env, stream)?;
+                Ok((MergeInput::Stream(reader), Some(subscription)))
+            }
+        }
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, MergeInput::Stream(_))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            MergeInput::Path(path) => path.to_string(),
+            MergeInput::Buffer(_) => "<buffer>".to_string(),
+            MergeInput::Stream(_) => "<stream>".to_string(),
+        }
+    }
+
+    pub fn into_read(self) -> io::Result<Box<dyn Read>> {
+        match self {
+            MergeInput::Path(path) => Ok(Box::new(File::open(path)?)),
+            MergeInput::Buffer(buffer) => Ok(Box::new(io::Cursor::new(buffer))),
+            MergeInput::Stream(reader) => Ok(Box::new(reader)),
+        }
+    }
+}
+
+/// Chunks sent but not yet read, shared by the `'data'` listener, which
+/// pauses the stream when too many pile up, and the reader, which resumes it.
+#[derive(Default)]
+struct Flow {
+    queued: usize,
+    paused: bool,
+}
+
+struct StreamSender {
+    sender: Sender<StreamChunk>,
+    flow: Arc<Mutex<Flow>>,
+}
+
+/// Blocking reader over chunks received from a Node stream.
+pub struct ChannelReader {
+    id: u32,
+    receiver: Receiver<StreamChunk>,
+    flow: Arc<Mutex<Flow>>,
+    resume: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl ChannelReader {
+    fn take_chunk(&mut self, data: Vec<u8>) {
+        self.chunk = data;
+        self.pos = 0;
+
+        let mut flow = self.flow.lock().unwrap();
+        flow.queued -= 1;
+        if flow.paused && flow.queued <= MAX_QUEUED_CHUNKS / 2 {
+            flow.paused = false;
+            self.resume.call((), ThreadsafeFunctionCallMode::NonBlocking);
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(StreamChunk::Data(data)) => self.take_chunk(data),
+                Ok(StreamChunk::End) | Err(_) => {
+                    self.finished = true;
+                }
+                Ok(StreamChunk::Error(message)) => {
+                    self.finished = true;
+                    return Err(io::Error::other(message));
+                }
+            }
+        }
+
+        let size = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+impl Drop for ChannelReader {
+    /// Stops forwarding chunks when the merge is done with the stream, and
+    /// lets a paused stream run to its end rather than hold on to it.
+    fn drop(&mut self) {
+        STREAM_SENDERS.lock().unwrap().remove(&self.id);
+        if self.flow.lock().unwrap().paused {
+            self.resume.call((), ThreadsafeFunctionCallMode::NonBlocking);
+        }
+    }
+}
+
+static STREAM_SENDERS: Mutex<BTreeMap<u32, StreamSender>> = Mutex::new(BTreeMap::new());
+static NEXT_STREAM_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Listeners added to a stream, removed again if the merge fails before it
+/// starts.
+struct Subscription {
+    id: u32,
+    stream: JsObject,
+    listeners: Vec<(&'static str, JsFunction)>,
+}
+
+impl Subscription {
+    fn unsubscribe(self, env: &Env) -> napi::Result<()> {
+        STREAM_SENDERS.lock().unwrap().remove(&self.id);
+        let remove_listener: JsFunction = self.stream.get_named_property("removeListener")?;
+        for (event, listener) in self.listeners {
+            remove_listener.call(
+                Some(&self.stream),
+                &[env.create_string(event)?.into_unknown(), listener.into_unknown()]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn subscribe_stream(env: &Env, stream: JsObject) -> napi::Result<(ChannelReader, Subscription)> {
+    let resume = bind(env, stream.get_named_property("resume")?, &stream, vec![])?;
+    let resume = resume.create_threadsafe_function(0, |_: ThreadSafeCallContext<()>| {
+        Ok(Vec::<JsUnknown>::new())
+    })?;
+
+    let (sender, receiver) = mpsc::channel();
+    let flow = Arc::new(Mutex::new(Flow::default()));
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+    STREAM_SENDERS.lock().unwrap().insert(id, StreamSender {
+        sender,
+        flow: flow.clone(),
+    });
+
+    let mut subscription = Subscription {
+        id,
+        stream,
+        listeners: vec![],
+    };
+    for (event, name, listener) in [
+        ("data", "onData", on_stream_data as napi::Callback),
+        ("end", "onEnd", on_stream_end),
+        ("error", "onError", on_stream_error),
+    ] {
+        if let Err(err) = listen(env, &mut subscription, event, env.create_function(name, listener)?) {
+            subscription.unsubscribe(env)?;
+            return Err(err);
+        }
+    }
+
+    let reader = ChannelReader {
+        id,
+        receiver,
+        flow,
+        resume,
+        chunk: vec![],
+        pos: 0,
+        finished: false,
+    };
+    Ok((reader, subscription))
+}
+
+/// Another handle to a value of the current scope.
+fn to_unknown(env: &Env, value: &impl NapiRaw) -> JsUnknown {
+    unsafe { JsUnknown::from_raw_unchecked(env.raw(), value.raw()) }
+}
+
+/// Binds `function` to `this` and leading `args`.
+fn bind(
+    env: &Env,
+    function: JsFunction,
+    this: &JsObject,
+    args: Vec<JsUnknown>
+) -> napi::Result<JsFunction> {
+    let function = function.coerce_to_object()?;
+    let bind: JsFunction = function.get_named_property("bind")?;
+    let mut bind_args = vec![to_unknown(env, this)];
+    bind_args.extend(args);
+    bind.call(Some(&function), &bind_args)?.try_into()
+}
+
+/// Adds `listener` for `event`, called with the stream as `this` and the
+/// subscription id as first argument.
+fn listen(
+    env: &Env,
+    subscription: &mut Subscription,
+    event: &'static str,
+    listener: JsFunction
+) -> napi::Result<()> {
+    let stream = &subscription.stream;
+    let bound = bind(env, listener, stream, vec![env.create_uint32(subscription.id)?.into_unknown()])?;
+
+    let on: JsFunction = stream.get_named_property("on")?;
+    on.call(Some(stream), &[env.create_string(event)?.into_unknown(), to_unknown(env, &bound)])?;
+    subscription.listeners.push((event, bound));
+    Ok(())
+}
+
+fn send(ctx: &CallContext, chunk: StreamChunk) -> napi::Result<JsUndefined> {
+    let id = ctx.get::<JsNumber>(0)?.get_uint32()?;
+    let mut senders = STREAM_SENDERS.lock().unwrap();
+    let last = !matches!(chunk, StreamChunk::Data(_));
+    let mut pause = false;
+    if let Some(stream_sender) = senders.get(&id) {
+        let data = matches!(chunk, StreamChunk::Data(_));
+        // Locked before sending, so the reader never takes a chunk not yet counted.
+        let mut flow = stream_sender.flow.lock().unwrap();
+        // The merge task may already be gone (e.g. it failed), nothing to do then.
+        if stream_sender.sender.send(chunk).is_ok() && data {
+            flow.queued += 1;
+            if !flow.paused && flow.queued >= MAX_QUEUED_CHUNKS {
+                flow.paused = true;
+                pause = true;
+            }
+        }
+    }
+    if last {
+        senders.remove(&id);
+    }
+    drop(senders);
+
+    if pause {
+        let stream = ctx.this::<JsObject>()?;
+        let pause: JsFunction = stream.get_named_property("pause")?;
+        pause.call_without_args(Some(&stream))?;
+    }
+    ctx.env.get_undefined()
+}
+
+#[js_function(2)]
+fn on_stream_data(ctx: CallContext) -> napi::Result<JsUndefined> {
+    let chunk = ctx.get::<JsUnknown>(1)?;
+    let data = if chunk.is_buffer()? {
+        ctx.get::<JsBuffer>(1)?.into_value()?.to_vec()
+    } else if chunk.get_type()? == ValueType::String {
+        chunk.coerce_to_string()?.into_utf8()?.as_slice().to_vec()
+    } else {
+        return send(&ctx, StreamChunk::Error("Stream chunks must be Buffer or string".to_string()));
+    };
+    send(&ctx, StreamChunk::Data(data))
+}
+
+#[js_function(1)]
+fn on_stream_end(ctx: CallContext) -> napi::Result<JsUndefined> {
+    send(&ctx, StreamChunk::End)
+}
+
+#[js_function(2)]
+fn on_stream_error(ctx: CallContext) -> napi::Result<JsUndefined> {
+    let error = ctx.get::<JsUnknown>(1)?.coerce_to_string()?.into_utf8()?.into_owned()?;
+    send(&ctx, StreamChunk::Error(error))
+}
@@ -1,29 +1,167 @@
 use std::cmp::Ordering;
 use std::collections::{ HashMap, HashSet };
 use std::fs::File;
+use std::io::{ self, Read };
 use std::vec;
 
 use csv::{ ByteRecord, ByteRecordsIntoIter, Reader, ReaderBuilder, Writer, WriterBuilder };
 use napi::Task;
-use napi::bindgen_prelude::Undefined;
 use napi::threadsafe_function::{ ThreadsafeFunction, ErrorStrategy };
 
 use futures::executor;
 
-use crate::{ MergeStrategy, DeduplicateStrategy };
+use crate::{
+    MergeStrategy,
+    DeduplicateStrategy,
+    KeyNormalization,
+    EmptyKeyPolicy,
+    TieBreak,
+    ColumnAggregation,
+    ColumnConflictPolicy,
+    CrossJoinLimits,
+    MergeStats,
+    KeyGroup,
+};
 
-use super::deduplicate::{ Side };
+use super::aggregate::Aggregation;
+use super::audit::AuditLog;
+use super::conflict::ConflictResolver;
+use super::deduplicate::{ GroupCounts, GroupResolver, Side, Similarity, StrategyHandler };
+use super::input::MergeInput;
+use super::sink::RowSink;
+use super::spill::SpillGroup;
+use super::normalize::normalize_key;
+
+type KeyedRecord = (ByteRecord, Option<Vec<u8>>);
+
+struct RecordSource {
+    lines: ByteRecordsIntoIter<Box<dyn Read>>,
+    mapping: HashMap<usize, Option<usize>>,
+    key_index: usize,
+    side: Side,
+    path: String,
+    line: usize,
+    /// Key of the last record read, to catch input not sorted by the
+    /// normalized key.
+    last_key: Option<Vec<u8>>,
+}
+
+/// Collects rows sharing a key so that they reach the deduplicate handler
+/// in the order requested by `TieBreak`. Each side spills to a temporary
+/// file beyond `groupMemoryLimit`.
+struct TieBreakGroup {
+    tie_break: TieBreak,
+    key_value: Option<Vec<u8>>,
+    left: SpillGroup,
+    right: SpillGroup,
+}
+
+impl TieBreakGroup {
+    fn new(tie_break: TieBreak, memory_limit: Option<usize>) -> Self {
+        TieBreakGroup {
+            tie_break,
+            key_value: None,
+            left: SpillGroup::new(memory_limit, None),
+            right: SpillGroup::new(memory_limit, None),
+        }
+    }
+
+    fn add_row(
+        &mut self,
+        handler: &mut impl StrategyHandler,
+        row: ByteRecord,
+        value: Vec<u8>,
+        side: Side
+    ) -> Result<(), csv::Error> {
+        if self.tie_break == TieBreak::InputOrder {
+            return handler.add_row(row, value, side);
+        }
+        if self.key_value.as_ref() != Some(&value) {
+            self.flush(handler)?;
+            self.key_value = Some(value);
+        }
+        match side {
+            Side::Left => self.left.push(row, side),
+            Side::Right => self.right.push(row, side),
+        }
+    }
+
+    fn flush(&mut self, handler: &mut impl StrategyHandler) -> Result<(), csv::Error> {
+        let Some(key_value) = self.key_value.take() else {
+            return Ok(());
+        };
+        let (first, second) = match self.tie_break {
+            TieBreak::RightFirst => (&mut self.right, &mut self.left),
+            _ => (&mut self.left, &mut self.right),
+        };
+        for group in [first, second] {
+            for row in group.rows()? {
+                let (row, side) = row?;
+                handler.add_row(row, key_value.clone(), side)?;
+            }
+            group.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Rows whose key is empty or a null token under
+/// `EmptyKeyPolicy::MatchEachOther`. They take no part in the sorted merge,
+/// so they may sit anywhere in either input, and are matched with each other
+/// once it is done. `rejects` receives them under `EmptyKeyPolicy::Reject`.
+struct EmptyKeyRows {
+    left: SpillGroup,
+    right: SpillGroup,
+    rejects: Option<Writer<File>>,
+}
+
+impl EmptyKeyRows {
+    fn new(memory_limit: Option<usize>, rejects: Option<Writer<File>>) -> Self {
+        EmptyKeyRows {
+            left: SpillGroup::new(memory_limit, None),
+            right: SpillGroup::new(memory_limit, None),
+            rejects,
+        }
+    }
+
+    fn push(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        match side {
+            Side::Left => self.left.push(row, side),
+            Side::Right => self.right.push(row, side),
+        }
+    }
+}
 
 pub struct Merger {
-    left_file_path: String,
-    right_file_path: String,
+    left_input: Option<MergeInput>,
+    right_input: Option<MergeInput>,
+    left_name: String,
+    right_name: String,
     merge_strategy: MergeStrategy,
     deduplicate_strategy: DeduplicateStrategy,
     left_key: String,
     right_key: String,
     number_key: bool,
-    output: String,
     output_header_callback: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>,
+    key_normalization: Vec<KeyNormalization>,
+    empty_key_policy: EmptyKeyPolicy,
+    empty_key_reject_output: Option<String>,
+    /// Normalized like keys, see `extract_key`.
+    null_tokens: Vec<Vec<u8>>,
+    append_line_number: bool,
+    append_source_path: bool,
+    tie_break: TieBreak,
+    aggregations: Vec<ColumnAggregation>,
+    conflict_policies: Vec<ColumnConflictPolicy>,
+    conflict_report_output: Option<String>,
+    cross_join_limits: Option<CrossJoinLimits>,
+    group_memory_limit: Option<usize>,
+    similarity_columns: Option<Vec<String>>,
+    similarity_ignore_columns: Vec<String>,
+    audit_output: Option<String>,
+    group_callback: Option<GroupCallback>,
+    group_batch_size: usize,
+    group_count_columns: bool,
 }
 
 fn to_number(x: &[u8]) -> i64 {
@@ -32,32 +170,239 @@ fn to_number(x: &[u8]) -> i64 {
     string.parse::<i64>().expect(format!("Parse number: {}", string).as_str())
 }
 
+/// Resolves `Reduce` aggregations against the output headers.
+pub fn resolve_aggregations(
+    aggregations: &[ColumnAggregation],
+    output_headers: &[String]
+) -> Result<Vec<Aggregation>, csv::Error> {
+    aggregations
+        .iter()
+        .map(|aggregation| {
+            let index = output_headers
+                .iter()
+                .position(|header| *header == aggregation.column)
+                .ok_or_else(||
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown aggregation column: {}", aggregation.column)
+                    )
+                )?;
+            Ok(Aggregation {
+                index,
+                column: aggregation.column.clone(),
+                function: aggregation.function,
+                separator: aggregation.separator.clone().unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Resolves the columns rows are compared on for similarity, `None` when
+/// whole rows are compared.
+pub fn resolve_similarity(
+    similarity_columns: &Option<Vec<String>>,
+    similarity_ignore_columns: &[String],
+    output_headers: &[String]
+) -> Result<Option<Similarity>, csv::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    for column in similarity_columns.iter().flatten().chain(similarity_ignore_columns) {
+        if !output_headers.contains(column) {
+            return Err(invalid(format!("Unknown similarity column: {}", column)).into());
+        }
+    }
+
+    let columns = match (similarity_columns, similarity_ignore_columns.is_empty()) {
+        (None, true) => {
+            return Ok(None);
+        }
+        (Some(_), false) => {
+            return Err(
+                invalid(
+                    "similarityColumns and similarityIgnoreColumns are exclusive".to_string()
+                ).into()
+            );
+        }
+        (Some(columns), true) =>
+            columns
+                .iter()
+                .filter_map(|column| output_headers.iter().position(|header| header == column))
+                .collect(),
+        (None, false) =>
+            (0..output_headers.len())
+                .filter(|&i| !similarity_ignore_columns.contains(&output_headers[i]))
+                .collect(),
+    };
+    Ok(Some(Similarity::on_columns(columns)))
+}
+
+/// `groupCallback`, see `create_callback`.
+pub type GroupCallback = ThreadsafeFunction<Vec<KeyGroup>, ErrorStrategy::CalleeHandled>;
+
+/// Sends key groups to the JS callback, blocking once per batch, and maps
+/// the returned rows back onto the output headers. A throw or rows that are
+/// not objects of strings fail the merge.
+pub fn group_resolver(
+    callback: GroupCallback,
+    headers: Vec<String>
+) -> GroupResolver {
+    let to_object = |record: &ByteRecord, headers: &[String]| -> HashMap<String, String> {
+        headers
+            .iter()
+            .zip(record.iter())
+            .map(|(header, field)| (header.clone(), String::from_utf8_lossy(field).to_string()))
+            .collect()
+    };
+
+    Box::new(move |groups| {
+        let groups: Vec<KeyGroup> = groups
+            .into_iter()
+            .map(|group| KeyGroup {
+                key: String::from_utf8_lossy(&group.key_value).to_string(),
+                left: group.left
+                    .iter()
+                    .map(|record| to_object(record, &headers))
+                    .collect(),
+                right: group.right
+                    .iter()
+                    .map(|record| to_object(record, &headers))
+                    .collect(),
+            })
+            .collect();
+        let rows = executor
+            ::block_on(callback.call_async::<Vec<HashMap<String, String>>>(Ok(groups)))
+            .map_err(|err| io::Error::other(format!("groupCallback failed: {}", err.reason)))?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| {
+                    headers
+                        .iter()
+                        .map(|header| row.get(header).map_or("", |value| value.as_str()))
+                        .collect()
+                })
+                .collect()
+        )
+    })
+}
+
 impl Merger {
     pub fn create(
-        left_file_path: String,
-        right_file_path: String,
+        left_input: MergeInput,
+        right_input: MergeInput,
         merge_strategy: MergeStrategy,
         deduplicate_strategy: DeduplicateStrategy,
         left_key: String,
         right_key: String,
         number_key: bool,
-        output: String,
         output_header_callback: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>
     ) -> Merger {
         Merger {
-            left_file_path,
-            right_file_path,
+            left_name: left_input.name(),
+            right_name: right_input.name(),
+            left_input: Some(left_input),
+            right_input: Some(right_input),
             merge_strategy,
             deduplicate_strategy,
             left_key,
             right_key,
-            output,
             number_key,
             output_header_callback,
+            key_normalization: vec![],
+            empty_key_policy: EmptyKeyPolicy::MatchEachOther,
+            empty_key_reject_output: None,
+            null_tokens: vec![],
+            append_line_number: false,
+            append_source_path: false,
+            tie_break: TieBreak::InputOrder,
+            aggregations: vec![],
+            conflict_policies: vec![],
+            conflict_report_output: None,
+            cross_join_limits: None,
+            group_memory_limit: None,
+            similarity_columns: None,
+            similarity_ignore_columns: vec![],
+            audit_output: None,
+            group_callback: None,
+            group_batch_size: 1000,
+            group_count_columns: false,
         }
     }
 
-    pub fn handle(self) -> Result<(), csv::Error> {
+    pub fn with_key_normalization(&mut self, steps: Vec<KeyNormalization>) {
+        self.key_normalization = steps;
+    }
+
+    pub fn with_empty_key_policy(&mut self, policy: EmptyKeyPolicy, reject_output: Option<String>) {
+        self.empty_key_policy = policy;
+        self.empty_key_reject_output = reject_output;
+    }
+
+    /// Call after `with_key_normalization`, tokens are normalized with its
+    /// steps.
+    pub fn with_null_tokens(&mut self, tokens: Vec<String>) {
+        self.null_tokens = tokens
+            .iter()
+            .map(|token| normalize_key(token.as_bytes(), &self.key_normalization))
+            .collect();
+    }
+
+    pub fn with_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    pub fn with_aggregations(&mut self, aggregations: Vec<ColumnAggregation>) {
+        self.aggregations = aggregations;
+    }
+
+    pub fn with_conflict_policies(
+        &mut self,
+        policies: Vec<ColumnConflictPolicy>,
+        report_output: Option<String>
+    ) {
+        self.conflict_policies = policies;
+        self.conflict_report_output = report_output;
+    }
+
+    pub fn with_cross_join_limits(&mut self, limits: CrossJoinLimits) {
+        self.cross_join_limits = Some(limits);
+    }
+
+    pub fn with_group_memory_limit(&mut self, limit: usize) {
+        self.group_memory_limit = Some(limit);
+    }
+
+    pub fn with_similarity_columns(&mut self, columns: Option<Vec<String>>, ignore_columns: Vec<String>) {
+        self.similarity_columns = columns;
+        self.similarity_ignore_columns = ignore_columns;
+    }
+
+    pub fn with_audit_output(&mut self, path: String) {
+        self.audit_output = Some(path);
+    }
+
+    pub fn with_group_callback(
+        &mut self,
+        callback: GroupCallback,
+        batch_size: usize
+    ) {
+        self.group_callback = Some(callback);
+        self.group_batch_size = batch_size;
+    }
+
+    pub fn append_group_count_columns(&mut self) {
+        self.group_count_columns = true;
+    }
+
+    pub fn append_line_number(&mut self) {
+        self.append_line_number = true;
+    }
+
+    pub fn append_source_path(&mut self) {
+        self.append_source_path = true;
+    }
+
+    pub fn handle<S: RowSink>(mut self, output: &mut S) -> Result<MergeStats, csv::Error> {
         let mut left_reader = self.get_left_reader()?;
         let mut right_reader = self.get_right_reader()?;
 
@@ -91,28 +436,91 @@ impl Merger {
             0
         };
 
-        let mut writer = self.get_writer();
+        let mut write_headers = output_headers.clone();
+        write_headers.extend(self.get_provenance_headers());
 
-        writer.write_record(&output_headers).expect("write output headers");
+        if self.group_count_columns {
+            // Appended by the deduplicate handler, after everything the
+            // options below refer to.
+            let mut headers = write_headers.clone();
+            headers.extend(GroupCounts::HEADERS.map(String::from));
+            output.write_headers(&headers)?;
+        } else {
+            output.write_headers(&write_headers)?;
+        }
+
+        let mut empty_keys = EmptyKeyRows::new(
+            self.group_memory_limit,
+            self.get_rejects_writer(&write_headers)?
+        );
 
         let mut deduplicate_handler = DeduplicateStrategy::create(
             self.deduplicate_strategy,
-            &mut writer
+            output
         );
+        deduplicate_handler.with_aggregations(
+            resolve_aggregations(&self.aggregations, &write_headers)?
+        );
+        deduplicate_handler.with_conflict_resolver(self.get_conflict_resolver(&write_headers)?);
+        if let Some(limits) = &self.cross_join_limits {
+            deduplicate_handler.with_cross_join_limits(limits.clone());
+        }
+        if let Some(limit) = self.group_memory_limit {
+            deduplicate_handler.with_group_memory_limit(limit);
+        }
+        if
+            let Some(similarity) = resolve_similarity(
+                &self.similarity_columns,
+                &self.similarity_ignore_columns,
+                &write_headers
+            )?
+        {
+            deduplicate_handler.with_similarity(similarity);
+        }
+        if let Some(path) = &self.audit_output {
+            deduplicate_handler.with_audit_log(AuditLog::create(path, &write_headers)?);
+        }
+        if self.group_count_columns {
+            deduplicate_handler.with_group_counts();
+        }
+        if let Some(callback) = self.group_callback.clone() {
+            deduplicate_handler.with_group_resolver(
+                group_resolver(callback, write_headers.clone()),
+                self.group_batch_size
+            );
+        }
+
+        let mut tie_break_group = TieBreakGroup::new(self.tie_break, self.group_memory_limit);
 
-        let mut left_lines = left_reader.into_byte_records();
-        let mut right_lines = right_reader.into_byte_records();
+        let mut left_source = RecordSource {
+            lines: left_reader.into_byte_records(),
+            mapping: map_left_headers_to_union,
+            key_index: left_key_index,
+            side: Side::Left,
+            path: self.left_name.clone(),
+            line: 0,
+            last_key: None,
+        };
+        let mut right_source = RecordSource {
+            lines: right_reader.into_byte_records(),
+            mapping: map_right_headers_to_union,
+            key_index: right_key_index,
+            side: Side::Right,
+            path: self.right_name.clone(),
+            line: 0,
+            last_key: None,
+        };
 
         let mut left_line = self.read_record(
-            &mut left_lines,
-            &map_left_headers_to_union,
-            Some(left_key_index)
-        );
+            &mut left_source,
+            &mut deduplicate_handler,
+            &mut empty_keys
+        )?;
         let mut right_line = self.read_record(
-            &mut right_lines,
-            &map_right_headers_to_union,
-            Some(right_key_index)
-        );
+            &mut right_source,
+            &mut deduplicate_handler,
+            &mut empty_keys
+        )?;
 
         let mut old_left_value: Option<Vec<u8>> = None;
 
@@ -157,12 +565,18 @@ impl Merger {
             };
 
             if need_left_push {
-                deduplicate_handler.add_row(left_record.clone(), left_value.to_vec(), Side::Left)?;
+                tie_break_group.add_row(
+                    &mut deduplicate_handler,
+                    left_record.clone(),
+                    left_value.to_vec(),
+                    Side::Left
+                )?;
                 // self.write_row(&mut writer, &map_left_headers_to_union, left_record);
                 left_readed = false;
             }
             if need_right_push {
-                deduplicate_handler.add_row(
+                tie_break_group.add_row(
+                    &mut deduplicate_handler,
                     right_record.clone(),
                     right_value.to_vec(),
                     Side::Right
@@ -175,17 +589,17 @@ impl Merger {
                 left_readed = true;
                 old_left_value = Some(left_value.to_vec());
                 left_line = self.read_record(
-                    &mut left_lines,
-                    &map_left_headers_to_union,
-                    Some(left_key_index)
-                );
+                    &mut left_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
             } else {
                 right_readed = true;
                 right_line = self.read_record(
-                    &mut right_lines,
-                    &map_right_headers_to_union,
-                    Some(right_key_index)
-                );
+                    &mut right_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
             }
         }
 
@@ -194,7 +608,8 @@ impl Merger {
                 if left_readed {
                     match &self.merge_strategy {
                         MergeStrategy::Or | MergeStrategy::AndNot => {
-                            deduplicate_handler.add_row(
+                            tie_break_group.add_row(
+                                &mut deduplicate_handler,
                                 left_record.clone(),
                                 value.as_ref().unwrap().to_vec(),
                                 Side::Left
@@ -205,10 +620,10 @@ impl Merger {
                 }
 
                 left_line = self.read_record(
-                    &mut left_lines,
-                    &map_left_headers_to_union,
-                    Some(left_key_index)
-                );
+                    &mut left_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
                 left_readed = true;
             }
         }
@@ -218,7 +633,8 @@ impl Merger {
                 if right_readed {
                     match &self.merge_strategy {
                         MergeStrategy::Or => {
-                            deduplicate_handler.add_row(
+                            tie_break_group.add_row(
+                                &mut deduplicate_handler,
                                 right_record.clone(),
                                 value.as_ref().unwrap().to_vec(),
                                 Side::Right
@@ -229,36 +645,108 @@ impl Merger {
                 }
 
                 right_line = self.read_record(
-                    &mut right_lines,
-                    &map_right_headers_to_union,
-                    Some(right_key_index)
-                );
+                    &mut right_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
                 right_readed = true;
             }
         }
 
+        // `And` stops at the end of either input, but empty keys further on
+        // may still match or have to be rejected.
+        if
+            self.merge_strategy == MergeStrategy::And &&
+            matches!(self.empty_key_policy, EmptyKeyPolicy::MatchEachOther | EmptyKeyPolicy::Reject)
+        {
+            while left_line.is_some() {
+                left_line = self.read_record(
+                    &mut left_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
+            }
+            while right_line.is_some() {
+                right_line = self.read_record(
+                    &mut right_source,
+                    &mut deduplicate_handler,
+                    &mut empty_keys
+                )?;
+            }
+        }
+
+        tie_break_group.flush(&mut deduplicate_handler)?;
+        self.flush_empty_keys(&mut empty_keys, &mut deduplicate_handler)?;
         deduplicate_handler.flush()?;
-        Ok(())
+        if let Some(rejects) = &mut empty_keys.rejects {
+            rejects.flush()?;
+        }
+        Ok(MergeStats {
+            cross_join_limited_keys: deduplicate_handler.cross_join_limited_keys(),
+            warnings: deduplicate_handler.warnings(),
+        })
+    }
+
+    fn get_conflict_resolver(
+        &self,
+        output_headers: &[String]
+    ) -> Result<ConflictResolver, csv::Error> {
+        let mut policies = HashMap::new();
+        for conflict_policy in &self.conflict_policies {
+            let index = output_headers
+                .iter()
+                .position(|header| *header == conflict_policy.column)
+                .ok_or_else(||
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown conflict policy column: {}", conflict_policy.column)
+                    )
+                )?;
+            policies.insert(index, conflict_policy.policy);
+        }
+        ConflictResolver::create(
+            policies,
+            output_headers.to_vec(),
+            self.conflict_report_output.as_ref()
+        )
     }
 
-    fn get_writer(&self) -> Writer<File> {
-        WriterBuilder::new().delimiter(b'\t').from_path(&self.output).expect("open output file")
+    fn get_rejects_writer(
+        &self,
+        output_headers: &Vec<String>
+    ) -> Result<Option<Writer<File>>, csv::Error> {
+        if self.empty_key_policy != EmptyKeyPolicy::Reject {
+            return Ok(None);
+        }
+        let path = self.empty_key_reject_output
+            .as_ref()
+            .ok_or_else(||
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "emptyKeyRejectOutput is required for EmptyKeyPolicy.Reject"
+                )
+            )?;
+        let mut writer = WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+        writer.write_record(output_headers)?;
+        Ok(Some(writer))
     }
 
-    fn get_left_reader(&self) -> Result<Reader<File>, csv::Error> {
-        self.build_reader(&self.left_file_path)
+    fn get_left_reader(&mut self) -> Result<Reader<Box<dyn Read>>, csv::Error> {
+        let input = self.left_input.take().expect("left input is read once");
+        self.build_reader(input)
     }
-    fn get_right_reader(&self) -> Result<Reader<File>, csv::Error> {
-        self.build_reader(&self.right_file_path)
+    fn get_right_reader(&mut self) -> Result<Reader<Box<dyn Read>>, csv::Error> {
+        let input = self.right_input.take().expect("right input is read once");
+        self.build_reader(input)
     }
 
-    fn build_reader(&self, path: &String) -> Result<Reader<File>, csv::Error> {
-        ReaderBuilder::new().delimiter(b'\t').from_path(path)
+    fn build_reader(&self, input: MergeInput) -> Result<Reader<Box<dyn Read>>, csv::Error> {
+        Ok(ReaderBuilder::new().delimiter(b'\t').from_reader(input.into_read()?))
     }
 
-    fn get_headers(
+    fn get_headers<R: Read>(
         &self,
-        reader: &mut Reader<File>,
+        reader: &mut Reader<R>,
         key: &String
     ) -> Result<(Vec<Option<String>>, Option<usize>), csv::Error> {
         if !reader.has_headers() {
@@ -316,6 +804,19 @@ impl Merger {
             .collect()
     }
 
+    fn get_provenance_headers(&self) -> Vec<String> {
+        let mut headers = vec![];
+        if self.append_line_number {
+            headers.push("__left_line".to_string());
+            headers.push("__right_line".to_string());
+        }
+        if self.append_source_path {
+            headers.push("__left_source".to_string());
+            headers.push("__right_source".to_string());
+        }
+        headers
+    }
+
     fn map_file_headers_to_output(
         &self,
         output_headers: &Vec<String>,
@@ -334,71 +835,278 @@ impl Merger {
 
     fn read_record(
         &self,
-        iter: &mut ByteRecordsIntoIter<File>,
-        mapping: &HashMap<usize, Option<usize>>,
-        key_index: Option<usize>
-    ) -> Option<(ByteRecord, Option<Vec<u8>>)> {
-        if let Some(Ok(record)) = iter.next() {
-            let mut values: Vec<&[u8]> = Vec::with_capacity(mapping.len());
-            for i in 0..mapping.len() {
-                let rec_key = *mapping.get(&i).unwrap();
+        source: &mut RecordSource,
+        deduplicate_handler: &mut impl StrategyHandler,
+        empty_keys: &mut EmptyKeyRows
+    ) -> Result<Option<KeyedRecord>, csv::Error> {
+        for result in source.lines.by_ref() {
+            let record = match result {
+                Ok(record) => record,
+                Err(err) if err.is_io_error() => return Err(err),
+                Err(_) => break,
+            };
+            source.line += 1;
+            let line_value = source.line.to_string();
+            let mut values: Vec<&[u8]> = Vec::with_capacity(source.mapping.len());
+            for i in 0..source.mapping.len() {
+                let rec_key = *source.mapping.get(&i).unwrap();
                 if let Some(rec_key) = rec_key {
                     values.push(&record[rec_key]);
                 } else {
                     values.push(b"");
                 }
             }
+            if self.append_line_number {
+                self.push_provenance(&mut values, &source.side, line_value.as_bytes());
+            }
+            if self.append_source_path {
+                self.push_provenance(&mut values, &source.side, source.path.as_bytes());
+            }
             let new_record = ByteRecord::from_iter(&values);
-            if let Some(key_index) = key_index {
-                let key_value = record.get(key_index).unwrap().to_owned();
-                return Some((new_record, Some(key_value.clone())));
+            let key_value = self.extract_key(record.get(source.key_index).unwrap_or(b""));
+
+            if !key_value.is_empty() {
+                self.check_key_order(source, &key_value)?;
+                return Ok(Some((new_record, Some(key_value))));
+            }
+
+            match self.empty_key_policy {
+                EmptyKeyPolicy::MatchEachOther => {
+                    empty_keys.push(new_record, source.side.clone())?;
+                }
+                EmptyKeyPolicy::NeverMatch => {
+                    if self.keeps_unmatched(&source.side) {
+                        deduplicate_handler.add_unique_row(new_record, source.side.clone())?;
+                    }
+                }
+                EmptyKeyPolicy::Drop => (),
+                EmptyKeyPolicy::Reject => {
+                    if let Some(rejects) = &mut empty_keys.rejects {
+                        rejects.write_byte_record(&new_record)?;
+                    }
+                }
             }
-            return Some((new_record, None));
         }
-        None
+        Ok(None)
+    }
+
+    /// Writes the rows of `EmptyKeyPolicy::MatchEachOther` as one more key
+    /// group, matched when both inputs have some. Left rows go first unless
+    /// `TieBreak::RightFirst`.
+    fn flush_empty_keys(
+        &self,
+        empty_keys: &mut EmptyKeyRows,
+        deduplicate_handler: &mut impl StrategyHandler
+    ) -> Result<(), csv::Error> {
+        let matched = !empty_keys.left.is_empty() && !empty_keys.right.is_empty();
+        let (keep_left, keep_right) = match self.merge_strategy {
+            MergeStrategy::And => (matched, matched),
+            MergeStrategy::Or => (true, true),
+            MergeStrategy::AndNot => (!matched, false),
+        };
+        let mut groups = [
+            (&mut empty_keys.left, keep_left),
+            (&mut empty_keys.right, keep_right),
+        ];
+        if self.tie_break == TieBreak::RightFirst {
+            groups.reverse();
+        }
+        for (group, keep) in groups {
+            if keep {
+                for row in group.rows()? {
+                    let (row, side) = row?;
+                    deduplicate_handler.add_row(row, vec![], side)?;
+                }
+            }
+            group.clear();
+        }
+        Ok(())
+    }
+
+    /// Fails when a key sorts before the previous one of the same input,
+    /// which would otherwise silently break the join. A `Distinct` union of
+    /// both inputs ignores the key and needs no order.
+    fn check_key_order(&self, source: &mut RecordSource, key_value: &[u8]) -> Result<(), csv::Error> {
+        if
+            matches!(self.deduplicate_strategy, DeduplicateStrategy::Distinct) &&
+            self.merge_strategy == MergeStrategy::Or
+        {
+            return Ok(());
+        }
+        if let Some(last_key) = &source.last_key {
+            if self.compare(key_value, last_key).is_lt() {
+                let side = match source.side {
+                    Side::Left => "Left",
+                    Side::Right => "Right",
+                };
+                return Err(
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} input is not sorted by key at line {}: {:?} follows {:?}",
+                            side,
+                            source.line,
+                            String::from_utf8_lossy(key_value),
+                            String::from_utf8_lossy(last_key)
+                        )
+                    ).into()
+                );
+            }
+        }
+        source.last_key = Some(key_value.to_vec());
+        Ok(())
+    }
+
+    fn push_provenance<'r>(&self, values: &mut Vec<&'r [u8]>, side: &Side, value: &'r [u8]) {
+        match side {
+            Side::Left => values.extend([value, b""]),
+            Side::Right => values.extend([b"", value]),
+        }
+    }
+
+    /// Normalizes the key cell, returning an empty key when it matches a null
+    /// token. Tokens are normalized too, so `" NULL "` matches `NULL` after
+    /// `Trim`.
+    fn extract_key(&self, cell: &[u8]) -> Vec<u8> {
+        let key_value = normalize_key(cell, &self.key_normalization);
+        if self.null_tokens.contains(&key_value) {
+            return vec![];
+        }
+        key_value
+    }
+
+    fn keeps_unmatched(&self, side: &Side) -> bool {
+        match self.merge_strategy {
+            MergeStrategy::Or => true,
+            MergeStrategy::And => false,
+            MergeStrategy::AndNot => *side == Side::Left,
+        }
     }
 
     fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
-        if self.number_key { to_number(a).cmp(&to_number(b)) } else { a.cmp(b) }
+        if !self.number_key {
+            return a.cmp(b);
+        }
+        match (a.is_empty(), b.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => to_number(a).cmp(&to_number(b)),
+        }
     }
 }
 
-pub struct AsyncMergeTask {
-    pub left_path: String,
-    pub right_path: String,
-    pub output: String,
+/// Everything needed to build a `Merger`, collected from `MergeOptions` on
+/// the JS thread.
+pub struct MergeSettings {
+    pub left_input: Option<MergeInput>,
+    pub right_input: Option<MergeInput>,
     pub merge_strategy: MergeStrategy,
     pub deduplicate_strategy: DeduplicateStrategy,
     pub left_key: String,
     pub right_key: String,
     pub is_number_key: Option<bool>,
+    pub key_normalization: Vec<KeyNormalization>,
+    pub empty_key_policy: EmptyKeyPolicy,
+    pub empty_key_reject_output: Option<String>,
+    pub null_tokens: Vec<String>,
+    pub append_line_number: bool,
+    pub append_source_path: bool,
+    pub tie_break: TieBreak,
+    pub aggregations: Vec<ColumnAggregation>,
+    pub conflict_policies: Vec<ColumnConflictPolicy>,
+    pub conflict_report_output: Option<String>,
+    pub cross_join_limits: Option<CrossJoinLimits>,
+    pub group_memory_limit: Option<usize>,
+    pub similarity_columns: Option<Vec<String>>,
+    pub similarity_ignore_columns: Vec<String>,
+    pub audit_output: Option<String>,
     pub output_header_callback: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>,
+    pub group_callback: Option<GroupCallback>,
+    pub group_batch_size: usize,
+    pub group_count_columns: bool,
 }
 
-impl Task for AsyncMergeTask {
-    type Output = Undefined;
-    type JsValue = ();
+impl MergeSettings {
+    /// Whether either side reads a Node stream, so the merge has to run off
+    /// the libuv pool, see `spawn_task`.
+    pub fn has_stream_input(&self) -> bool {
+        [&self.left_input, &self.right_input]
+            .iter()
+            .any(|input| input.as_ref().is_some_and(MergeInput::is_stream))
+    }
 
-    fn compute(&mut self) -> napi::Result<()> {
-        let merger = Merger::create(
-            self.left_path.to_owned(),
-            self.right_path.to_owned(),
+    pub fn create_merger(&mut self) -> Merger {
+        let mut merger = Merger::create(
+            self.left_input.take().expect("merge inputs are consumed once"),
+            self.right_input.take().expect("merge inputs are consumed once"),
             self.merge_strategy,
             self.deduplicate_strategy,
             self.left_key.to_owned(),
             self.right_key.to_owned(),
             self.is_number_key.unwrap_or(false),
-            self.output.to_owned(),
             self.output_header_callback.clone()
         );
-
+        merger.with_key_normalization(self.key_normalization.clone());
+        merger.with_empty_key_policy(self.empty_key_policy, self.empty_key_reject_output.clone());
+        merger.with_null_tokens(self.null_tokens.clone());
+        merger.with_tie_break(self.tie_break);
+        merger.with_aggregations(self.aggregations.clone());
+        merger.with_conflict_policies(
+            self.conflict_policies.clone(),
+            self.conflict_report_output.clone()
+        );
+        if let Some(limits) = &self.cross_join_limits {
+            merger.with_cross_join_limits(limits.clone());
+        }
+        if let Some(limit) = self.group_memory_limit {
+            merger.with_group_memory_limit(limit);
+        }
+        merger.with_similarity_columns(
+            self.similarity_columns.clone(),
+            self.similarity_ignore_columns.clone()
+        );
+        if let Some(path) = &self.audit_output {
+            merger.with_audit_output(path.clone());
+        }
+        if let Some(callback) = &self.group_callback {
+            merger.with_group_callback(callback.clone(), self.group_batch_size);
+        }
+        if self.group_count_columns {
+            merger.append_group_count_columns();
+        }
+        if self.append_line_number {
+            merger.append_line_number();
+        }
+        if self.append_source_path {
+            merger.append_source_path();
+        }
         merger
-            .handle()
-            .map_err(|err| napi::Error::new(napi::Status::GenericFailure, err.to_string()))?;
-        Ok(())
+    }
+}
+
+pub struct AsyncMergeTask {
+    pub settings: MergeSettings,
+    pub output: String,
+}
+
+impl Task for AsyncMergeTask {
+    type Output = MergeStats;
+    type JsValue = MergeStats;
+
+    fn compute(&mut self) -> napi::Result<MergeStats> {
+        let to_napi_error = |err: csv::Error|
+            napi::Error::new(napi::Status::GenericFailure, err.to_string());
+
+        let mut writer = WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_path(&self.output)
+            .map_err(to_napi_error)?;
+
+        self.settings.create_merger().handle(&mut writer).map_err(to_napi_error)
     }
 
-    fn resolve(&mut self, _env: napi::Env, _output: ()) -> napi::Result<Undefined> {
-        Ok(())
+    fn resolve(&mut self, _env: napi::Env, output: MergeStats) -> napi::Result<MergeStats> {
+        Ok(output)
     }
-}
\ No newline at end of file
+}
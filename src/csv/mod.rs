@@ -1,4 +1,13 @@
+pub mod aggregate;
+pub mod audit;
+pub mod conflict;
 pub mod deduplicate;
+pub mod input;
 pub mod merge;
+pub mod normalize;
+pub mod rows;
+pub mod sink;
+pub mod spill;
 pub mod transform;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod value;
\ No newline at end of file
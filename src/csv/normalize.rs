@@ -0,0 +1,66 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::KeyNormalization;
+
+impl KeyNormalization {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            KeyNormalization::Trim => value.trim().to_string(),
+            KeyNormalization::Lowercase => value.to_lowercase(),
+            KeyNormalization::StripLeadingZeros => {
+                let stripped = value.trim_start_matches('0');
+                if stripped.is_empty() && !value.is_empty() {
+                    "0".to_string()
+                } else {
+                    stripped.to_string()
+                }
+            }
+            KeyNormalization::Nfkc => value.nfkc().collect(),
+            KeyNormalization::DigitsOnly =>
+                value
+                    .chars()
+                    .filter(|c| c.is_ascii_digit())
+                    .collect(),
+        }
+    }
+}
+
+/// Applies normalization steps to a key cell in the given order.
+/// The cell is returned untouched when there are no steps.
+pub fn normalize_key(value: &[u8], steps: &[KeyNormalization]) -> Vec<u8> {
+    if steps.is_empty() {
+        return value.to_vec();
+    }
+
+    let mut normalized = String::from_utf8_lossy(value).into_owned();
+    for step in steps {
+        normalized = step.apply(&normalized);
+    }
+    normalized.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_steps_keeps_value() {
+        assert_eq!(normalize_key(b" 007 ", &[]), b" 007 ");
+    }
+
+    #[test]
+    fn test_steps_applied_in_order() {
+        let steps = [KeyNormalization::Trim, KeyNormalization::StripLeadingZeros];
+        assert_eq!(normalize_key(b"  0042 ", &steps), b"42");
+        assert_eq!(normalize_key(b"000", &steps), b"0");
+
+        let steps = [KeyNormalization::Nfkc, KeyNormalization::Lowercase];
+        assert_eq!(normalize_key("ＡＢＣ".as_bytes(), &steps), b"abc");
+    }
+
+    #[test]
+    fn test_digits_only() {
+        let steps = [KeyNormalization::DigitsOnly];
+        assert_eq!(normalize_key(b"+1 (555) 010-99", &steps), b"155501099");
+    }
+}
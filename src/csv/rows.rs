@@ -0,0 +1,204 @@
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ self, Receiver };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+
+use csv::ByteRecord;
+use napi::bindgen_prelude::{ AsyncTask, Either };
+use napi::{ CallContext, Env, JsFunction, JsObject, JsUnknown, Task };
+use napi_derive::{ js_function, napi };
+
+use crate::RowFormat;
+
+use super::merge::MergeSettings;
+use super::sink::{ ChannelSink, RowMessage };
+use super::utils::spawn_task;
+
+/// Number of batches the merge thread may run ahead of the JS consumer.
+const CHANNEL_CAPACITY: usize = 4;
+
+pub fn spawn_merge_rows(mut settings: MergeSettings, batch_size: usize) -> Receiver<RowMessage> {
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+
+    thread::spawn(move || {
+        let mut sink = ChannelSink::new(sender.clone(), batch_size);
+        let result = panic::catch_unwind(
+            AssertUnwindSafe(|| settings.create_merger().handle(&mut sink))
+        );
+        let message = match result {
+            Ok(Ok(_)) => {
+                return;
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(panic) =>
+                panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "merge failed".to_string()),
+        };
+        let _ = sender.send(RowMessage::Error(message));
+    });
+
+    receiver
+}
+
+struct RowsState {
+    receiver: Mutex<Option<Receiver<RowMessage>>>,
+    headers: Mutex<Vec<String>>,
+    closed: AtomicBool,
+}
+
+#[napi]
+pub struct MergeRowsIterator {
+    state: Arc<RowsState>,
+    row_format: RowFormat,
+    /// Wait for batches off the libuv pool, which stream inputs read on.
+    stream_input: bool,
+}
+
+impl MergeRowsIterator {
+    pub fn create(
+        env: Env,
+        receiver: Receiver<RowMessage>,
+        row_format: RowFormat,
+        stream_input: bool
+    ) -> napi::Result<JsObject> {
+        let iterator = MergeRowsIterator {
+            state: Arc::new(RowsState {
+                receiver: Mutex::new(Some(receiver)),
+                headers: Mutex::new(vec![]),
+                closed: AtomicBool::new(false),
+            }),
+            row_format,
+            stream_input,
+        };
+
+        let mut object: JsObject = iterator.into_instance(env)?.as_object(env);
+        let symbol: JsFunction = env.get_global()?.get_named_property("Symbol")?;
+        let async_iterator: JsUnknown = symbol.coerce_to_object()?.get_named_property("asyncIterator")?;
+        object.set_property(async_iterator, env.create_function("asyncIterator", return_this)?)?;
+        Ok(object)
+    }
+}
+
+#[napi]
+impl MergeRowsIterator {
+    #[napi(
+        ts_return_type = "Promise<IteratorResult<Array<Record<string, string>> | Array<Array<string>>>>"
+    )]
+    pub fn next(&self, env: Env) -> napi::Result<Either<AsyncTask<NextRowsTask>, JsObject>> {
+        let task = NextRowsTask {
+            state: self.state.clone(),
+            row_format: self.row_format,
+        };
+        if self.stream_input {
+            Ok(Either::B(spawn_task(&env, task)?))
+        } else {
+            Ok(Either::A(AsyncTask::new(task)))
+        }
+    }
+
+    /// Stops the merge early, e.g. on `break` inside `for await`.
+    #[napi(js_name = "return", ts_return_type = "IteratorResult<undefined>")]
+    pub fn close(&self, env: Env) -> napi::Result<JsObject> {
+        self.state.closed.store(true, Ordering::SeqCst);
+        if let Ok(mut receiver) = self.state.receiver.try_lock() {
+            receiver.take();
+        }
+        iterator_result(env, None)
+    }
+}
+
+pub struct NextRowsTask {
+    state: Arc<RowsState>,
+    row_format: RowFormat,
+}
+
+impl Task for NextRowsTask {
+    type Output = Option<Vec<ByteRecord>>;
+    type JsValue = JsObject;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let mut receiver = self.state.receiver.lock().unwrap();
+        loop {
+            let message = match receiver.as_ref() {
+                Some(receiver) if !self.state.closed.load(Ordering::SeqCst) => receiver.recv(),
+                _ => {
+                    receiver.take();
+                    return Ok(None);
+                }
+            };
+            match message {
+                Ok(RowMessage::Headers(headers)) => {
+                    *self.state.headers.lock().unwrap() = headers;
+                }
+                Ok(RowMessage::Batch(rows)) => {
+                    return Ok(Some(rows));
+                }
+                Ok(RowMessage::Error(message)) => {
+                    receiver.take();
+                    return Err(napi::Error::new(napi::Status::GenericFailure, message));
+                }
+                Err(_) => {
+                    receiver.take();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<JsObject> {
+        let rows = match output {
+            Some(rows) => rows,
+            None => {
+                return iterator_result(env, None);
+            }
+        };
+
+        let headers = self.state.headers.lock().unwrap();
+        let mut batch = env.create_array_with_length(rows.len())?;
+        for (index, row) in rows.iter().enumerate() {
+            let value = match self.row_format {
+                RowFormat::Object => {
+                    let mut object = env.create_object()?;
+                    for (header, field) in headers.iter().zip(row.iter()) {
+                        object.set_named_property(
+                            header,
+                            env.create_string(&String::from_utf8_lossy(field))?
+                        )?;
+                    }
+                    object
+                }
+                RowFormat::Array => {
+                    let mut array = env.create_array_with_length(row.len())?;
+                    for (i, field) in row.iter().enumerate() {
+                        array.set_element(
+                            i as u32,
+                            env.create_string(&String::from_utf8_lossy(field))?
+                        )?;
+                    }
+                    array
+                }
+            };
+            batch.set_element(index as u32, value)?;
+        }
+        iterator_result(env, Some(batch))
+    }
+}
+
+fn iterator_result(env: Env, value: Option<JsObject>) -> napi::Result<JsObject> {
+    let mut result = env.create_object()?;
+    result.set_named_property("done", env.get_boolean(value.is_none())?)?;
+    match value {
+        Some(value) => result.set_named_property("value", value)?,
+        None => result.set_named_property("value", env.get_undefined()?)?,
+    }
+    Ok(result)
+}
+
+#[js_function(1)]
+fn return_this(ctx: CallContext) -> napi::Result<JsObject> {
+    ctx.this()
+}
@@ -0,0 +1,95 @@
+use std::io::{ self, Write };
+use std::sync::mpsc::SyncSender;
+
+use csv::{ ByteRecord, Writer };
+
+/// Destination of rows produced by the merge and deduplicate handlers.
+pub trait RowSink {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), csv::Error>;
+    fn write_byte_record(&mut self, record: &ByteRecord) -> Result<(), csv::Error>;
+    fn flush(&mut self) -> Result<(), csv::Error>;
+}
+
+impl<W: Write> RowSink for Writer<W> {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), csv::Error> {
+        self.write_record(headers)
+    }
+
+    fn write_byte_record(&mut self, record: &ByteRecord) -> Result<(), csv::Error> {
+        Writer::write_byte_record(self, record)
+    }
+
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        Writer::flush(self)?;
+        Ok(())
+    }
+}
+
+/// Keeps rows in memory, headers included as the first record.
+impl RowSink for Vec<ByteRecord> {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), csv::Error> {
+        self.push(ByteRecord::from(headers.to_vec()));
+        Ok(())
+    }
+
+    fn write_byte_record(&mut self, record: &ByteRecord) -> Result<(), csv::Error> {
+        self.push(record.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        Ok(())
+    }
+}
+
+pub enum RowMessage {
+    Headers(Vec<String>),
+    Batch(Vec<ByteRecord>),
+    Error(String),
+}
+
+/// Sends rows in batches over a bounded channel consumed from JS.
+pub struct ChannelSink {
+    sender: SyncSender<RowMessage>,
+    batch: Vec<ByteRecord>,
+    batch_size: usize,
+}
+
+impl ChannelSink {
+    pub fn new(sender: SyncSender<RowMessage>, batch_size: usize) -> Self {
+        ChannelSink {
+            sender,
+            batch: Vec::with_capacity(batch_size),
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    /// Blocks while the channel is full, so a slow consumer holds the merge back.
+    fn send(&mut self, message: RowMessage) -> Result<(), csv::Error> {
+        self.sender
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "rows consumer is closed").into())
+    }
+}
+
+impl RowSink for ChannelSink {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), csv::Error> {
+        self.send(RowMessage::Headers(headers.to_vec()))
+    }
+
+    fn write_byte_record(&mut self, record: &ByteRecord) -> Result<(), csv::Error> {
+        self.batch.push(record.clone());
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), csv::Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        self.send(RowMessage::Batch(batch))
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs::File;
+use std::hash::{ Hash, Hasher };
+use std::mem;
+use std::ops::Range;
+use std::slice;
+
+use csv::{ ByteRecord, ByteRecordsIntoIter, Position, ReaderBuilder, Writer, WriterBuilder };
+use tempfile::NamedTempFile;
+
+use super::deduplicate::{ Side, Similarity };
+
+/// Rows of a key group. Kept in memory up to `budget` bytes, beyond that
+/// they are written out to temporary files. An unordered group appends to a
+/// single file. In a sorted group every spill is a sorted run, and the runs
+/// are merged when read back, so that similar rows come out next to each
+/// other. Runs are merged `FAN_IN` at a time as they pile up, which bounds
+/// the files open while reading.
+pub struct SpillGroup {
+    budget: Option<usize>,
+    order: Option<Similarity>,
+    rows: Vec<(ByteRecord, Side)>,
+    size: usize,
+    len: usize,
+    runs: Vec<SpillRun>,
+    /// Writes to the only run of an unordered group.
+    appender: Option<Writer<File>>,
+}
+
+/// A spilled file, with the number of merges its rows went through.
+struct SpillRun {
+    file: NamedTempFile,
+    level: usize,
+}
+
+/// Most runs merged, or read back, at once.
+const FAN_IN: usize = 16;
+
+impl SpillGroup {
+    pub fn new(budget: Option<usize>, order: Option<Similarity>) -> Self {
+        SpillGroup {
+            budget,
+            order,
+            rows: vec![],
+            size: 0,
+            len: 0,
+            runs: vec![],
+            appender: None,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+    }
+
+    pub fn set_order(&mut self, order: Option<Similarity>) {
+        self.order = order;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, row: ByteRecord, side: Side) -> Result<(), csv::Error> {
+        self.size += row.as_slice().len() + row.len() * mem::size_of::<usize>();
+        self.rows.push((row, side));
+        self.len += 1;
+        if let Some(budget) = self.budget {
+            if self.size > budget {
+                self.spill()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rows in insertion order, or in the order of the group's similarity.
+    /// Can be called again, or rewound, to read the group from the start.
+    pub fn rows(&mut self) -> Result<GroupRows<'_>, csv::Error> {
+        self.sort_rows();
+        if let Some(appender) = &mut self.appender {
+            appender.flush()?;
+        }
+        // Leaves room for the rows still in memory.
+        while self.runs.len() >= FAN_IN {
+            self.merge_runs(0..FAN_IN)?;
+        }
+
+        let mut runs = open_runs(&self.runs)?;
+        runs.push(Run::Memory(&self.rows, self.rows.iter()));
+        GroupRows::create(self.order.as_ref(), runs)
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.appender = None;
+        self.runs.clear();
+        self.size = 0;
+        self.len = 0;
+    }
+
+    fn sort_rows(&mut self) {
+        if let Some(order) = &self.order {
+            self.rows.sort_by(|a, b| order.cmp(&a.0, &b.0));
+        }
+    }
+
+    fn spill(&mut self) -> Result<(), csv::Error> {
+        self.sort_rows();
+        if self.order.is_none() {
+            if self.appender.is_none() {
+                let file = NamedTempFile::new()?;
+                self.appender = Some(temp_writer(&file)?);
+                self.runs.push(SpillRun { file, level: 0 });
+            }
+            let appender = self.appender.as_mut().unwrap();
+            for (row, side) in self.rows.drain(..) {
+                write_spilled(appender, row, &side)?;
+            }
+        } else {
+            let file = NamedTempFile::new()?;
+            let mut writer = temp_writer(&file)?;
+            for (row, side) in self.rows.drain(..) {
+                write_spilled(&mut writer, row, &side)?;
+            }
+            writer.flush()?;
+            self.runs.push(SpillRun { file, level: 0 });
+
+            // Merges the latest runs once `FAN_IN` of them have gone through
+            // as many merges, like carrying in a counter.
+            loop {
+                let count = self.runs.len();
+                if count < FAN_IN {
+                    break;
+                }
+                let level = self.runs[count - 1].level;
+                if self.runs[count - FAN_IN..].iter().any(|run| run.level != level) {
+                    break;
+                }
+                self.merge_runs(count - FAN_IN..count)?;
+            }
+        }
+
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Replaces consecutive runs by a single sorted one.
+    fn merge_runs(&mut self, range: Range<usize>) -> Result<(), csv::Error> {
+        let file = NamedTempFile::new()?;
+        let mut writer = temp_writer(&file)?;
+        let level = self.runs[range.clone()].iter().map(|run| run.level).max().unwrap_or(0) + 1;
+        for row in GroupRows::create(self.order.as_ref(), open_runs(&self.runs[range.clone()])?)? {
+            let (row, side) = row?;
+            write_spilled(&mut writer, row, &side)?;
+        }
+        writer.flush()?;
+
+        self.runs.splice(range, [SpillRun { file, level }]);
+        Ok(())
+    }
+}
+
+fn open_runs(runs: &[SpillRun]) -> Result<Vec<Run<'static>>, csv::Error> {
+    runs.iter()
+        .map(|run| Ok(Run::File(temp_records(&run.file)?)))
+        .collect()
+}
+
+fn write_spilled(writer: &mut Writer<File>, mut row: ByteRecord, side: &Side) -> Result<(), csv::Error> {
+    row.push_field(side_field(side));
+    writer.write_byte_record(&row)
+}
+
+enum Run<'g> {
+    File(ByteRecordsIntoIter<File>),
+    Memory(&'g [(ByteRecord, Side)], slice::Iter<'g, (ByteRecord, Side)>),
+}
+
+impl<'g> Run<'g> {
+    fn next(&mut self) -> Result<Option<(ByteRecord, Side)>, csv::Error> {
+        match self {
+            Run::File(records) =>
+                match records.next() {
+                    Some(record) => {
+                        let mut record = record?;
+                        let side = field_side(record.get(record.len() - 1));
+                        record.truncate(record.len() - 1);
+                        Ok(Some((record, side)))
+                    }
+                    None => Ok(None),
+                }
+            Run::Memory(_, rows) => Ok(rows.next().cloned()),
+        }
+    }
+
+    fn rewind(&mut self) -> Result<(), csv::Error> {
+        match self {
+            Run::File(records) => records.reader_mut().seek(Position::new()),
+            Run::Memory(all, rows) => {
+                *rows = all.iter();
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct GroupRows<'g> {
+    order: Option<&'g Similarity>,
+    runs: Vec<Run<'g>>,
+    heads: Vec<Option<(ByteRecord, Side)>>,
+}
+
+impl<'g> GroupRows<'g> {
+    fn create(order: Option<&'g Similarity>, runs: Vec<Run<'g>>) -> Result<Self, csv::Error> {
+        let mut group_rows = GroupRows {
+            order,
+            heads: Vec::with_capacity(runs.len()),
+            runs,
+        };
+        group_rows.read_heads()?;
+        Ok(group_rows)
+    }
+
+    /// Starts over from the first row, without reopening the files.
+    pub fn rewind(&mut self) -> Result<(), csv::Error> {
+        for run in &mut self.runs {
+            run.rewind()?;
+        }
+        self.read_heads()
+    }
+
+    fn read_heads(&mut self) -> Result<(), csv::Error> {
+        self.heads.clear();
+        for run in &mut self.runs {
+            self.heads.push(run.next()?);
+        }
+        Ok(())
+    }
+}
+
+impl<'g> Iterator for GroupRows<'g> {
+    type Item = Result<(ByteRecord, Side), csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Earlier runs win ties, which keeps the merge stable.
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((record, _)) = head else {
+                continue;
+            };
+            next = match (next, self.order) {
+                (None, _) => Some(i),
+                (Some(_), None) => {
+                    break;
+                }
+                (Some(j), Some(order)) => {
+                    let (best, _) = self.heads[j].as_ref().unwrap();
+                    if order.cmp(record, best).is_lt() { Some(i) } else { Some(j) }
+                }
+            };
+        }
+
+        let i = next?;
+        match self.runs[i].next() {
+            Ok(head) => mem::replace(&mut self.heads[i], head).map(Ok),
+            Err(err) => {
+                self.heads[i] = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+const PARTITIONS: usize = 64;
+
+fn side_field(side: &Side) -> &'static [u8] {
+    match side {
+        Side::Left => b"L",
+        Side::Right => b"R",
+    }
+}
+
+fn field_side(field: Option<&[u8]>) -> Side {
+    match field {
+        Some(b"R") => Side::Right,
+        _ => Side::Left,
+    }
+}
+
+fn temp_writer(file: &NamedTempFile) -> Result<Writer<File>, csv::Error> {
+    Ok(WriterBuilder::new().has_headers(false).flexible(true).from_writer(file.reopen()?))
+}
+
+fn temp_records(file: &NamedTempFile) -> Result<ByteRecordsIntoIter<File>, csv::Error> {
+    let reader = ReaderBuilder::new().has_headers(false).flexible(true).from_reader(file.reopen()?);
+    Ok(reader.into_byte_records())
+}
+
+/// Rows still to be deduplicated once the set of seen values outgrew its
+/// budget. They are partitioned by the hash of their value into temporary
+/// files, so that one partition at a time has to fit in memory.
+pub struct PartitionedRows {
+    partitions: Vec<(NamedTempFile, Writer<File>)>,
+    sequence: usize,
+}
+
+impl PartitionedRows {
+    /// Starts with the values already seen, which no later row may repeat.
+    pub fn create(seen: impl Iterator<Item = Vec<u8>>) -> Result<Self, csv::Error> {
+        let mut partitions = Vec::with_capacity(PARTITIONS);
+        for _ in 0..PARTITIONS {
+            let file = NamedTempFile::new()?;
+            let writer = temp_writer(&file)?;
+            partitions.push((file, writer));
+        }
+        let mut rows = PartitionedRows {
+            partitions,
+            sequence: 0,
+        };
+        for value in seen {
+            let record = ByteRecord::from(vec![b"S".as_slice(), &value]);
+            rows.partition(&value).write_byte_record(&record)?;
+        }
+        Ok(rows)
+    }
+
+    pub fn push(&mut self, value: &[u8], row: &ByteRecord, side: &Side) -> Result<(), csv::Error> {
+        let sequence = self.sequence.to_string();
+        self.sequence += 1;
+        let mut record = ByteRecord::from(vec![side_field(side), sequence.as_bytes(), value]);
+        record.extend(row);
+        self.partition(value).write_byte_record(&record)
+    }
+
+    /// Passes on the first row of every value not seen before, in the order
+    /// the rows were pushed.
+    pub fn write_distinct(
+        self,
+        mut write: impl FnMut(ByteRecord, Side) -> Result<(), csv::Error>
+    ) -> Result<(), csv::Error> {
+        let mut survivors = vec![];
+        for (file, mut writer) in self.partitions {
+            writer.flush()?;
+            drop(writer);
+
+            let output = NamedTempFile::new()?;
+            let mut output_writer = temp_writer(&output)?;
+            let mut seen: HashSet<Vec<u8>> = HashSet::new();
+            for record in temp_records(&file)? {
+                let record = record?;
+                match record.get(0) {
+                    Some(b"S") => {
+                        seen.insert(record.get(1).unwrap_or(b"").to_vec());
+                    }
+                    _ => {
+                        if seen.insert(record.get(2).unwrap_or(b"").to_vec()) {
+                            output_writer.write_byte_record(&record)?;
+                        }
+                    }
+                }
+            }
+            output_writer.flush()?;
+            survivors.push(output);
+        }
+
+        // Every partition keeps its rows in push order, merging them by
+        // sequence restores the order across partitions.
+        let mut runs = vec![];
+        for output in &survivors {
+            let mut records = temp_records(output)?;
+            let head = records.next().transpose()?;
+            runs.push((records, head));
+        }
+        loop {
+            let sequence = |record: &ByteRecord| -> usize {
+                std::str
+                    ::from_utf8(record.get(1).unwrap_or(b""))
+                    .ok()
+                    .and_then(|sequence| sequence.parse().ok())
+                    .unwrap_or(usize::MAX)
+            };
+            let next = runs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (_, head))| head.as_ref().map(|record| (i, sequence(record))))
+                .min_by_key(|(_, sequence)| *sequence)
+                .map(|(i, _)| i);
+            let Some(i) = next else {
+                break;
+            };
+            let (records, head) = &mut runs[i];
+            let record = mem::replace(head, records.next().transpose()?).unwrap();
+            let side = field_side(record.get(0));
+            write(record.iter().skip(3).collect(), side)?;
+        }
+        Ok(())
+    }
+
+    fn partition(&mut self, value: &[u8]) -> &mut Writer<File> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let index = (hasher.finish() as usize) % self.partitions.len();
+        &mut self.partitions[index].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(group: &mut SpillGroup) -> Vec<(String, Side)> {
+        group
+            .rows()
+            .unwrap()
+            .map(|row| {
+                let (record, side) = row.unwrap();
+                (String::from_utf8(record.as_slice().to_vec()).unwrap(), side)
+            })
+            .collect()
+    }
+
+    fn fill(group: &mut SpillGroup) {
+        for (value, side) in [("c", Side::Left), ("a", Side::Right), ("b", Side::Left), ("a", Side::Left)] {
+            group.push(ByteRecord::from(vec![value, ""]), side).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_spilled_rows_keep_insertion_order() {
+        let mut group = SpillGroup::new(Some(1), None);
+        fill(&mut group);
+
+        assert_eq!(group.len(), 4);
+        // Every row spilled, all to the same file.
+        assert_eq!(group.runs.len(), 1);
+        let expected = vec![
+            ("c".to_string(), Side::Left),
+            ("a".to_string(), Side::Right),
+            ("b".to_string(), Side::Left),
+            ("a".to_string(), Side::Left)
+        ];
+        assert_eq!(collect(&mut group), expected);
+        // Reading twice gives the same rows, as needed by the cross join.
+        assert_eq!(collect(&mut group), expected);
+    }
+
+    #[test]
+    fn test_spilled_runs_are_merged_sorted() {
+        let mut group = SpillGroup::new(Some(60), Some(Similarity::default()));
+        fill(&mut group);
+
+        assert_eq!(group.runs.len(), 1);
+        assert_eq!(collect(&mut group), [
+            ("a".to_string(), Side::Right),
+            ("a".to_string(), Side::Left),
+            ("b".to_string(), Side::Left),
+            ("c".to_string(), Side::Left),
+        ]);
+
+        group.clear();
+        assert!(group.is_empty());
+        assert_eq!(collect(&mut group), []);
+    }
+
+    #[test]
+    fn test_many_spills_keep_few_runs() {
+        let values: Vec<String> = (0..5000).map(|i| format!("{:04}", (i * 7919) % 5000)).collect();
+
+        let mut group = SpillGroup::new(Some(1), None);
+        for value in &values {
+            group.push(ByteRecord::from(vec![value.as_str()]), Side::Left).unwrap();
+        }
+        assert_eq!(group.runs.len(), 1);
+        let rows: Vec<String> = collect(&mut group).into_iter().map(|(value, _)| value).collect();
+        assert_eq!(rows, values);
+
+        let mut group = SpillGroup::new(Some(1), Some(Similarity::default()));
+        for value in &values {
+            group.push(ByteRecord::from(vec![value.as_str()]), Side::Left).unwrap();
+        }
+        assert!(group.runs.len() < FAN_IN * 4);
+        let mut sorted = values.clone();
+        sorted.sort();
+        let mut rows = group.rows().unwrap();
+        let first: Vec<String> = rows
+            .by_ref()
+            .take(3)
+            .map(|row| String::from_utf8(row.unwrap().0.as_slice().to_vec()).unwrap())
+            .collect();
+        assert_eq!(first, sorted[..3]);
+        rows.rewind().unwrap();
+        let all: Vec<String> = rows
+            .map(|row| String::from_utf8(row.unwrap().0.as_slice().to_vec()).unwrap())
+            .collect();
+        assert_eq!(all, sorted);
+        assert!(group.runs.len() < FAN_IN);
+    }
+
+    #[test]
+    fn test_partitioned_rows_keep_first_in_order() {
+        let mut rows = PartitionedRows::create(vec![b"a".to_vec()].into_iter()).unwrap();
+        for (value, side) in [
+            ("b", Side::Left),
+            ("a", Side::Left),
+            ("c", Side::Right),
+            ("b", Side::Right),
+            ("d", Side::Left),
+        ] {
+            rows.push(value.as_bytes(), &ByteRecord::from(vec![value, "x"]), &side).unwrap();
+        }
+
+        let mut written = vec![];
+        rows.write_distinct(|record, side| {
+            written.push((String::from_utf8(record.as_slice().to_vec()).unwrap(), side));
+            Ok(())
+        }).unwrap();
+        assert_eq!(written, [
+            ("bx".to_string(), Side::Left),
+            ("cx".to_string(), Side::Right),
+            ("dx".to_string(), Side::Left),
+        ]);
+    }
+}
@@ -1,17 +1,31 @@
-use std::{ error::Error };
+use std::{
+    collections::{ hash_map::DefaultHasher, HashMap, HashSet },
+    error::Error,
+    fs::File,
+    hash::{ Hash, Hasher },
+    io::{ BufRead, BufReader },
+};
 
 use csv::{ ReaderBuilder, WriterBuilder, ByteRecord };
 use futures::executor;
+use regex::Regex;
 use napi_derive::napi;
 use napi::{
-    bindgen_prelude::{ ToNapiValue },
+    bindgen_prelude::ClassInstance,
     threadsafe_function::{ ThreadsafeFunction, ErrorStrategy, ThreadSafeCallContext },
     JsFunction,
     Env,
     JsObject,
+    Task,
 };
 
-use super::utils::{ is_empty_file, create_empty_file };
+use crate::{ ColumnAggregation, DeduplicateOptions, DeduplicateStrategy };
+
+use super::audit::AuditLog;
+use super::deduplicate::{ GroupCounts, Side, StrategyHandler };
+use super::merge::{ group_resolver, resolve_aggregations, resolve_similarity, GroupCallback };
+use super::utils::{ is_empty_file, create_empty_file, create_callback };
+use super::value::TypedValue;
 
 #[derive(Debug)]
 struct TransformError {
@@ -37,8 +51,18 @@ fn create_transform_error(message: String) -> Result<(), Box<dyn std::error::Err
 #[derive(Debug, PartialEq)]
 #[napi]
 pub enum FieldType {
+    /// Integer.
     Number,
     String,
+    Float,
+    /// Compared exactly, unlike `Float`.
+    Decimal,
+    /// `%Y-%m-%d` unless `options.format` says otherwise.
+    Date,
+    /// `%Y-%m-%dT%H:%M:%S` unless `options.format` says otherwise.
+    DateTime,
+    /// true/false, yes/no, t/f, y/n or 1/0, in any case.
+    Boolean,
 }
 
 #[derive(Debug, PartialEq)]
@@ -49,6 +73,130 @@ pub enum Comparison {
     Le,
     Gt,
     Ge,
+    Ne,
+    /// Equal to one of `options.values`.
+    In,
+    Contains,
+    StartsWith,
+    EndsWith,
+    /// Matches the regular expression in `value`, anywhere in the cell.
+    Regex,
+    IsEmpty,
+    IsNotEmpty,
+    /// Between `value` and `options.to`, both included.
+    Between,
+    /// Text of the cell is one of the keys in the file at `value`, which does not
+    /// need to be sorted. Wrap in `Filter.not` to remove matching rows.
+    InKeySet,
+}
+
+/// What a filter does with a cell that does not parse as its field type.
+#[derive(Debug, PartialEq)]
+#[napi]
+pub enum InvalidValuePolicy {
+    NonMatch,
+    Match,
+    /// Fails the transform with the line of the cell.
+    Fail,
+}
+
+/// Operands of comparisons that need more than `value`.
+#[napi(object)]
+#[derive(Debug, Clone, Default)]
+pub struct FilterOptions {
+    /// Values accepted by `Comparison.In`.
+    pub values: Option<Vec<String>>,
+    /// Upper bound of `Comparison.Between`.
+    pub to: Option<String>,
+    /// Format of `Date` and `DateTime` values, built from `%Y`, `%m`, `%d`,
+    /// `%H`, `%M` and `%S`.
+    pub format: Option<String>,
+    /// `NonMatch` by default.
+    pub on_invalid: Option<InvalidValuePolicy>,
+    /// Column compared with instead of `value`, with `Eq`, `Ne`, `Lt`, `Le`,
+    /// `Gt`, `Ge`, `Contains`, `StartsWith` and `EndsWith`.
+    pub value_column: Option<String>,
+    /// Column of the `InKeySet` file holding the keys. Without it every line
+    /// of the file is a key.
+    pub key_column: Option<String>,
+    /// Delimiter of the `InKeySet` file when `key_column` is set, tab by
+    /// default.
+    pub key_delimiter: Option<u8>,
+    /// Keeps 64-bit hashes of the `InKeySet` keys instead of the keys, which
+    /// saves memory on long keys at the cost of rare false matches.
+    pub hash_keys: Option<bool>,
+}
+
+/// Keys loaded by `Comparison::InKeySet`.
+#[derive(Debug)]
+enum KeySet {
+    Keys(HashSet<Vec<u8>>),
+    Hashes(HashSet<u64>),
+}
+
+impl KeySet {
+    fn load(options: &FilterOptions, path: &str) -> Result<KeySet, Box<dyn Error>> {
+        let mut key_set = if options.hash_keys.unwrap_or(false) {
+            KeySet::Hashes(HashSet::new())
+        } else {
+            KeySet::Keys(HashSet::new())
+        };
+
+        match &options.key_column {
+            Some(column) => {
+                let mut reader = ReaderBuilder::new()
+                    .delimiter(options.key_delimiter.unwrap_or(b'\t'))
+                    .from_path(path)?;
+                let index = match reader.headers()?.iter().position(|header| header == column) {
+                    Some(index) => index,
+                    None => {
+                        return Err(
+                            Box::new(TransformError {
+                                message: format!("Not found key field {} in {}", column, path),
+                            })
+                        );
+                    }
+                };
+                for record in reader.byte_records() {
+                    key_set.insert(record?.get(index).unwrap_or_default());
+                }
+            }
+            None => {
+                for line in BufReader::new(File::open(path)?).split(b'\n') {
+                    let line = line?;
+                    let key = line.strip_suffix(b"\r").unwrap_or(&line);
+                    if !key.is_empty() {
+                        key_set.insert(key);
+                    }
+                }
+            }
+        }
+        Ok(key_set)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        match self {
+            KeySet::Keys(keys) => {
+                keys.insert(key.to_vec());
+            }
+            KeySet::Hashes(hashes) => {
+                hashes.insert(hash_key(key));
+            }
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        match self {
+            KeySet::Keys(keys) => keys.contains(key),
+            KeySet::Hashes(hashes) => hashes.contains(&hash_key(key)),
+        }
+    }
+}
+
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
 }
 
 #[derive(Debug)]
@@ -58,6 +206,13 @@ pub struct Filter {
     field_type: FieldType,
     comparison: Comparison,
     value: String,
+    options: FilterOptions,
+    regex: Option<Regex>,
+    /// `value`, `options.values` and `options.to` parsed as `field_type`.
+    operands: Vec<TypedValue>,
+    /// Index of `options.value_column`.
+    value_index: Option<usize>,
+    key_set: Option<KeySet>,
 }
 
 impl Clone for Filter {
@@ -68,6 +223,11 @@ impl Clone for Filter {
             field_type: self.field_type.clone(),
             comparison: self.comparison.clone(),
             value: self.value.clone(),
+            options: self.options.clone(),
+            regex: None,
+            operands: vec![],
+            value_index: None,
+            key_set: None,
         }
     }
 }
@@ -85,13 +245,300 @@ impl Filter {
             field,
             field_type: field_type.unwrap_or(FieldType::String),
             comparison: comparison.unwrap_or(Comparison::Eq),
+            options: FilterOptions::default(),
+            regex: None,
+            operands: vec![],
+            value_index: None,
+            key_set: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: FilterOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Resolves the field against the headers, compiles the regular
+    /// expression and parses the operands, once per transform.
+    fn compile(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        let index = headers.iter().position(|header| *header == self.field);
+        if index.is_none() {
+            return create_transform_error(format!("Not found filter field {}", self.field));
+        }
+        self.index = index;
+
+        if let Some(column) = &self.options.value_column {
+            self.value_index = headers.iter().position(|header| header == column);
+            if self.value_index.is_none() {
+                return create_transform_error(format!("Not found filter field {}", column));
+            }
+        }
+        self.parse_operands()?;
+
+        if self.comparison == Comparison::InKeySet {
+            self.key_set = Some(KeySet::load(&self.options, &self.value)?);
+        }
+        Ok(())
+    }
+
+    /// Fails on values that can never be compared, whatever the input.
+    fn parse_operands(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.options.value_column.is_some() {
+            return match self.comparison {
+                Comparison::Eq |
+                Comparison::Ne |
+                Comparison::Lt |
+                Comparison::Le |
+                Comparison::Gt |
+                Comparison::Ge |
+                Comparison::Contains |
+                Comparison::StartsWith |
+                Comparison::EndsWith => Ok(()),
+                _ =>
+                    create_transform_error(
+                        format!(
+                            "Filter on {} cannot compare columns with Comparison.{:?}",
+                            self.field,
+                            self.comparison
+                        )
+                    ),
+            };
+        }
+
+        match self.comparison {
+            Comparison::Regex => {
+                self.regex = Some(Regex::new(&self.value)?);
+            }
+            Comparison::In if self.options.values.is_none() => {
+                return create_transform_error(
+                    format!("Filter on {} needs values for Comparison.In", self.field)
+                );
+            }
+            Comparison::InKeySet if self.value.is_empty() => {
+                return create_transform_error(
+                    format!("Filter on {} needs a key file for Comparison.InKeySet", self.field)
+                );
+            }
+            Comparison::Between if self.options.to.is_none() => {
+                return create_transform_error(
+                    format!("Filter on {} needs an upper bound for Comparison.Between", self.field)
+                );
+            }
+            _ => (),
+        }
+
+        let operands: Vec<&String> = match self.comparison {
+            Comparison::In => self.options.values.iter().flatten().collect(),
+            Comparison::Between => vec![&self.value].into_iter().chain(&self.options.to).collect(),
+            Comparison::Eq |
+            Comparison::Ne |
+            Comparison::Lt |
+            Comparison::Le |
+            Comparison::Gt |
+            Comparison::Ge => vec![&self.value],
+            _ => vec![],
+        };
+        self.operands = vec![];
+        for operand in operands {
+            match TypedValue::parse(self.field_type, operand, self.options.format.as_deref()) {
+                Some(value) => self.operands.push(value),
+                None => {
+                    return create_transform_error(
+                        format!("Invalid {:?} filter value for {}: {}", self.field_type, self.field, operand)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn test(&self, record: &ByteRecord) -> Result<bool, Box<dyn Error>> {
+        let index = match self.index {
+            Some(index) => index,
+            None => {
+                return Ok(true);
+            }
+        };
+        let record_value_raw = match cell_text(record, index) {
+            Ok(value) => value,
+            Err(value) => {
+                return self.invalid_value(record, &value);
+            }
+        };
+        let value = match self.value_index {
+            Some(value_index) =>
+                match cell_text(record, value_index) {
+                    Ok(value) => value,
+                    Err(value) => {
+                        return self.invalid_value(record, &value);
+                    }
+                }
+            None => self.value.as_str(),
+        };
+
+        // These look at the text of the cell, whatever the field type.
+        match self.comparison {
+            Comparison::Contains => {
+                return Ok(record_value_raw.contains(value));
+            }
+            Comparison::StartsWith => {
+                return Ok(record_value_raw.starts_with(value));
+            }
+            Comparison::EndsWith => {
+                return Ok(record_value_raw.ends_with(value));
+            }
+            Comparison::Regex => {
+                return Ok(self.regex.as_ref().is_some_and(|regex| regex.is_match(record_value_raw)));
+            }
+            Comparison::IsEmpty => {
+                return Ok(record_value_raw.is_empty());
+            }
+            Comparison::IsNotEmpty => {
+                return Ok(!record_value_raw.is_empty());
+            }
+            Comparison::InKeySet => {
+                return Ok(
+                    self.key_set.as_ref().is_some_and(|key_set| key_set.contains(record_value_raw.as_bytes()))
+                );
+            }
+            _ => (),
+        }
+
+        let parse = |text| TypedValue::parse(self.field_type, text, self.options.format.as_deref());
+        let parsed_value = match parse(record_value_raw) {
+            Some(value) => value,
+            None => {
+                return self.invalid_value(record, record_value_raw);
+            }
+        };
+        let column_operands;
+        let operands = match self.value_index {
+            Some(_) => {
+                column_operands = match parse(value) {
+                    Some(operand) => [operand],
+                    None => {
+                        return self.invalid_value(record, value);
+                    }
+                };
+                &column_operands[..]
+            }
+            None => &self.operands[..],
+        };
+        let cmp = |i: usize| parsed_value.compare(&operands[i]);
+
+        Ok(match self.comparison {
+            Comparison::Eq => cmp(0).is_eq(),
+            Comparison::Ne => cmp(0).is_ne(),
+            Comparison::Lt => cmp(0).is_lt(),
+            Comparison::Le => cmp(0).is_le(),
+            Comparison::Gt => cmp(0).is_gt(),
+            Comparison::Ge => cmp(0).is_ge(),
+            Comparison::In => (0..self.operands.len()).any(|i| cmp(i).is_eq()),
+            Comparison::Between => cmp(0).is_ge() && cmp(1).is_le(),
+            _ => true,
+        })
+    }
+
+    fn invalid_value(&self, record: &ByteRecord, value: &str) -> Result<bool, Box<dyn Error>> {
+        match self.options.on_invalid.unwrap_or(InvalidValuePolicy::NonMatch) {
+            InvalidValuePolicy::NonMatch => Ok(false),
+            InvalidValuePolicy::Match => Ok(true),
+            InvalidValuePolicy::Fail => {
+                let line = record.position().map_or(0, |position| position.line());
+                Err(
+                    Box::new(TransformError {
+                        message: format!(
+                            "Invalid {:?} value for {} on line {}: {}",
+                            self.field_type,
+                            self.field,
+                            line,
+                            value
+                        ),
+                    })
+                )
+            }
+        }
+    }
+}
+
+/// Text of a cell, or its lossy decoding when it is not UTF-8. A missing
+/// cell reads as empty.
+fn cell_text(record: &ByteRecord, index: usize) -> Result<&str, String> {
+    let raw = record.get(index).unwrap_or_default();
+    std::str::from_utf8(raw).map_err(|_| String::from_utf8_lossy(raw).to_string())
+}
+
+/// Filters combined with AND, OR and NOT. Field indices are resolved once
+/// per transform by `Transform::parse_filters`.
+#[derive(Debug, Clone)]
+pub enum FilterTree {
+    Condition(Box<Filter>),
+    /// Matches when all filters match, or when there are none.
+    And(Vec<FilterTree>),
+    /// Matches when any filter matches, never when there are none.
+    Or(Vec<FilterTree>),
+    Not(Box<FilterTree>),
+}
+
+impl FilterTree {
+    fn resolve(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        match self {
+            FilterTree::Condition(filter) => filter.compile(headers),
+            FilterTree::And(filters) | FilterTree::Or(filters) =>
+                filters.iter_mut().try_for_each(|filter| filter.resolve(headers)),
+            FilterTree::Not(filter) => filter.resolve(headers),
+        }
+    }
+
+    /// Checks the filter values, which does not need the headers.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            FilterTree::Condition(filter) => filter.clone().parse_operands(),
+            FilterTree::And(filters) | FilterTree::Or(filters) =>
+                filters.iter().try_for_each(|filter| filter.validate()),
+            FilterTree::Not(filter) => filter.validate(),
+        }
+    }
+
+    fn test(&self, record: &ByteRecord) -> Result<bool, Box<dyn Error>> {
+        match self {
+            FilterTree::Condition(filter) => filter.test(record),
+            FilterTree::And(filters) => {
+                for filter in filters {
+                    if !filter.test(record)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            FilterTree::Or(filters) => {
+                for filter in filters {
+                    if filter.test(record)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            FilterTree::Not(filter) => Ok(!filter.test(record)?),
         }
     }
 }
 
+impl From<&Filter> for FilterTree {
+    fn from(filter: &Filter) -> Self {
+        FilterTree::Condition(Box::new(filter.clone()))
+    }
+}
+
+impl From<&FilterTree> for FilterTree {
+    fn from(filter: &FilterTree) -> Self {
+        filter.clone()
+    }
+}
+
 #[napi(js_name = "Filter")]
 pub struct JsFilter {
-    inner: Filter,
+    inner: FilterTree,
 }
 
 #[napi]
@@ -101,20 +548,84 @@ impl JsFilter {
         field: String,
         value: String,
         field_type: Option<FieldType>,
-        comparison: Option<Comparison>
+        comparison: Option<Comparison>,
+        options: Option<FilterOptions>
     ) -> Self {
         Self {
-            inner: Filter::create(field, value, field_type, comparison),
+            inner: FilterTree::Condition(
+                Box::new(
+                    Filter::create(field, value, field_type, comparison).with_options(
+                        options.unwrap_or_default()
+                    )
+                )
+            ),
+        }
+    }
+
+    /// Matches rows matching all of the filters.
+    #[napi]
+    pub fn and(filters: Vec<ClassInstance<JsFilter>>) -> JsFilter {
+        JsFilter {
+            inner: FilterTree::And(
+                filters
+                    .iter()
+                    .map(|filter| filter.inner.clone())
+                    .collect()
+            ),
+        }
+    }
+
+    /// Matches rows matching any of the filters.
+    #[napi]
+    pub fn or(filters: Vec<ClassInstance<JsFilter>>) -> JsFilter {
+        JsFilter {
+            inner: FilterTree::Or(
+                filters
+                    .iter()
+                    .map(|filter| filter.inner.clone())
+                    .collect()
+            ),
+        }
+    }
+
+    /// Matches rows the filter does not match.
+    #[napi]
+    pub fn not(filter: &JsFilter) -> JsFilter {
+        JsFilter {
+            inner: FilterTree::Not(Box::new(filter.inner.clone())),
         }
     }
 }
 
+/// Deduplicate strategy applied to the transformed rows, which must be
+/// sorted by the key column.
+pub struct Deduplication {
+    /// Not needed by `Distinct`.
+    pub key: Option<String>,
+    pub strategy: DeduplicateStrategy,
+    pub aggregations: Vec<ColumnAggregation>,
+    pub similarity_columns: Option<Vec<String>>,
+    pub similarity_ignore_columns: Vec<String>,
+    pub group_memory_limit: Option<usize>,
+    pub audit_output: Option<String>,
+    pub group_count_columns: bool,
+    pub group_callback: Option<GroupCallback>,
+    pub group_batch_size: usize,
+}
+
+/// Called with batches of rows keyed by header, returns whether to keep each.
+/// See `create_callback`.
+pub type RowFilter = ThreadsafeFunction<Vec<HashMap<String, String>>, ErrorStrategy::CalleeHandled>;
+
 pub struct Transform {
     path: String,
     delimiter: u8,
     append_line_number: bool,
-    filters: Vec<Filter>,
+    filters: Vec<FilterTree>,
     columns_transform: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>,
+    deduplication: Option<Deduplication>,
+    row_filter: Option<RowFilter>,
+    row_filter_batch_size: usize,
 }
 
 impl Transform {
@@ -128,6 +639,9 @@ impl Transform {
             delimiter: b'\t',
             filters: vec![],
             columns_transform: None,
+            deduplication: None,
+            row_filter: None,
+            row_filter_batch_size: 1000,
         }
     }
 
@@ -146,8 +660,21 @@ impl Transform {
         self.columns_transform = Some(func);
     }
 
-    pub fn add_filter(&mut self, filter: &Filter) {
-        self.filters.push(filter.clone());
+    pub fn add_filter<F: Into<FilterTree>>(&mut self, filter: F) {
+        self.filters.push(filter.into());
+    }
+
+    pub fn set_deduplication(&mut self, deduplication: Deduplication) {
+        self.deduplication = Some(deduplication);
+    }
+
+    pub fn set_row_filter(
+        &mut self,
+        func: RowFilter,
+        batch_size: usize
+    ) {
+        self.row_filter = Some(func);
+        self.row_filter_batch_size = batch_size.max(1);
     }
 
     pub fn save_to(&mut self, output: String) -> Result<(), Box<dyn Error>> {
@@ -190,38 +717,113 @@ impl Transform {
             write_headers.push("__line_number".to_string());
         }
 
-        writer.write_record(write_headers)?;
+        let (key_index, strategy) = match &self.deduplication {
+            Some(deduplication) => {
+                let key_index = match &deduplication.key {
+                    Some(key) =>
+                        match write_headers.iter().position(|header| header == key) {
+                            Some(key_index) => key_index,
+                            None => {
+                                return create_transform_error(format!("Not found key field {}", key));
+                            }
+                        }
+                    None => 0,
+                };
+                (key_index, deduplication.strategy)
+            }
+            None => (0, DeduplicateStrategy::KeepAll),
+        };
 
+        match &self.deduplication {
+            Some(deduplication) if deduplication.group_count_columns => {
+                let mut headers = write_headers.clone();
+                headers.extend(GroupCounts::HEADERS.map(String::from));
+                writer.write_record(headers)?;
+            }
+            _ => writer.write_record(&write_headers)?,
+        }
+
+        let mut deduplicate_handler = DeduplicateStrategy::create(strategy, &mut writer);
+        if let Some(deduplication) = &self.deduplication {
+            deduplicate_handler.with_aggregations(
+                resolve_aggregations(&deduplication.aggregations, &write_headers)?
+            );
+            if
+                let Some(similarity) = resolve_similarity(
+                    &deduplication.similarity_columns,
+                    &deduplication.similarity_ignore_columns,
+                    &write_headers
+                )?
+            {
+                deduplicate_handler.with_similarity(similarity);
+            }
+            if let Some(limit) = deduplication.group_memory_limit {
+                deduplicate_handler.with_group_memory_limit(limit);
+            }
+            if let Some(path) = &deduplication.audit_output {
+                deduplicate_handler.with_audit_log(AuditLog::create(path, &write_headers)?);
+            }
+            if deduplication.group_count_columns {
+                deduplicate_handler.with_group_counts();
+            }
+            if let Some(callback) = deduplication.group_callback.clone() {
+                deduplicate_handler.with_group_resolver(
+                    group_resolver(callback, write_headers.clone()),
+                    deduplication.group_batch_size
+                );
+            }
+        }
+
+        let mut write_row = |mut record: ByteRecord, line: usize| -> Result<(), Box<dyn Error>> {
+            let line_value = line.to_string();
+            if self.columns_transform.is_some() && headers.iter().any(|(_, h)| h.is_none()) {
+                let mut rec_vec: Vec<&[u8]> = record
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| {
+                        let r = headers.iter().find(|(i, _)| index == i);
+                        r.is_some() && r.unwrap().1.is_some()
+                    })
+                    .map(|(_, head)| head)
+                    .collect();
+                if self.append_line_number {
+                    rec_vec.push(line_value.as_bytes());
+                }
+                record = ByteRecord::from_iter(rec_vec);
+            } else if self.append_line_number {
+                let mut rec_vec: Vec<&[u8]> = record.iter().collect();
+                rec_vec.push(line_value.as_bytes());
+                record = ByteRecord::from_iter(rec_vec);
+            }
+            let key_value = record.get(key_index).unwrap_or(b"").to_vec();
+            deduplicate_handler.add_row(record, key_value, Side::Left)?;
+            Ok(())
+        };
+
+        // Rows waiting for the row filter, with their line numbers.
+        let mut batch: Vec<(ByteRecord, usize)> = vec![];
         let mut line = 1;
         for res in reader.byte_records() {
-            let mut record = res?;
-            if self.test_record(&record) {
-                let line_value = line.to_string();
-                if self.columns_transform.is_some() && headers.iter().any(|(_, h)| h.is_none()) {
-                    let mut rec_vec: Vec<&[u8]> = record
-                        .into_iter()
-                        .enumerate()
-                        .filter(|(index, _)| {
-                            let r = headers.iter().find(|(i, _)| index == i);
-                            r.is_some() && r.unwrap().1.is_some()
-                        })
-                        .map(|(_, head)| head)
-                        .collect();
-                    if self.append_line_number {
-                        rec_vec.push(line_value.as_bytes());
+            let record = res?;
+            if self.test_record(&record)? {
+                if self.row_filter.is_some() {
+                    batch.push((record, line));
+                    if batch.len() >= self.row_filter_batch_size {
+                        for (record, line) in self.filter_rows(&mut batch, &orig_headers)? {
+                            write_row(record, line)?;
+                        }
                     }
-                    record = ByteRecord::from_iter(rec_vec);
-                } else if self.append_line_number {
-                    let mut rec_vec: Vec<&[u8]> = record.iter().collect();
-                    rec_vec.push(line_value.as_bytes());
-                    record = ByteRecord::from_iter(rec_vec);
+                } else {
+                    write_row(record, line)?;
                 }
-                writer.write_byte_record(&record)?;
             }
             line += 1;
         }
+        for (record, line) in self.filter_rows(&mut batch, &orig_headers)? {
+            write_row(record, line)?;
+        }
 
-        writer.flush()?;
+        deduplicate_handler.flush()?;
 
         Ok(())
     }
@@ -237,59 +839,61 @@ impl Transform {
         }
     }
 
-    fn parse_filters(&mut self, headers: &Vec<String>) -> Result<(), Box<dyn Error>> {
-        self.filters
-            .iter_mut()
-            .map(
-                |filter| -> Result<(), Box<dyn Error>> {
-                    let index = headers.iter().position(|header| header.to_owned() == filter.field);
-                    if index.is_none() {
-                        return create_transform_error(
-                            format!("Not found filter field {}", filter.field)
-                        );
-                    }
-                    filter.index = index;
-                    Ok(())
-                }
-            )
-            .find(|x| x.is_err())
-            .unwrap_or(Ok(()))
-    }
-    fn test_record(&self, record: &ByteRecord) -> bool {
-        self.filters.iter().all(|filter| -> bool {
-            if let Some(index) = filter.index {
-                let record_value_raw = std::str::from_utf8(record.get(index).unwrap()).unwrap();
-
-                return match filter.field_type {
-                    FieldType::Number => {
-                        let filter_value = filter.value.parse::<i64>().unwrap();
-                        let parsed_value = record_value_raw.parse::<i64>().unwrap();
-                        let cmp = parsed_value.cmp(&filter_value);
-
-                        match filter.comparison {
-                            Comparison::Eq => cmp.is_eq(),
-                            Comparison::Lt => cmp.is_lt(),
-                            Comparison::Le => cmp.is_le(),
-                            Comparison::Gt => cmp.is_gt(),
-                            Comparison::Ge => cmp.is_ge(),
-                        }
-                    }
-                    FieldType::String => {
-                        let filter_value = filter.value.to_owned();
-                        let cmp = record_value_raw.to_string().cmp(&filter_value);
-
-                        match filter.comparison {
-                            Comparison::Eq => cmp.is_eq(),
-                            Comparison::Lt => cmp.is_lt(),
-                            Comparison::Le => cmp.is_le(),
-                            Comparison::Gt => cmp.is_gt(),
-                            Comparison::Ge => cmp.is_ge(),
-                        }
-                    }
-                };
+    /// Drains `batch`, keeping the rows the row filter returns true for.
+    fn filter_rows(
+        &self,
+        batch: &mut Vec<(ByteRecord, usize)>,
+        headers: &[String]
+    ) -> Result<Vec<(ByteRecord, usize)>, Box<dyn Error>> {
+        let row_filter = match &self.row_filter {
+            Some(row_filter) if !batch.is_empty() => row_filter,
+            _ => {
+                return Ok(std::mem::take(batch));
             }
-            true
-        })
+        };
+        let rows = batch
+            .iter()
+            .map(|(record, _)| {
+                headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(header, field)| (header.clone(), String::from_utf8_lossy(field).to_string()))
+                    .collect()
+            })
+            .collect();
+        let keep = executor
+            ::block_on(row_filter.call_async::<Vec<bool>>(Ok(rows)))
+            .map_err(|err| TransformError {
+                message: format!("Row filter failed: {}", err.reason),
+            })?;
+        if keep.len() != batch.len() {
+            return Err(
+                Box::new(TransformError {
+                    message: format!("Row filter returned {} results for {} rows", keep.len(), batch.len()),
+                })
+            );
+        }
+        Ok(
+            batch
+                .drain(..)
+                .zip(keep)
+                .filter(|(_, keep)| *keep)
+                .map(|(row, _)| row)
+                .collect()
+        )
+    }
+
+    fn parse_filters(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        self.filters.iter_mut().try_for_each(|filter| filter.resolve(headers))
+    }
+
+    fn test_record(&self, record: &ByteRecord) -> Result<bool, Box<dyn Error>> {
+        for filter in &self.filters {
+            if !filter.test(record)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 }
 
@@ -316,9 +920,38 @@ impl JsTransform {
         self.inner.append_line_number();
     }
 
+    /// Throws on filter values that do not parse as the field type.
     #[napi]
-    pub fn add_filter(&mut self, filter: &JsFilter) {
+    pub fn add_filter(&mut self, filter: &JsFilter) -> Result<(), napi::Error> {
+        filter.inner
+            .validate()
+            .map_err(|err| napi::Error::new(napi::Status::InvalidArg, err.to_string()))?;
         self.inner.add_filter(&filter.inner);
+        Ok(())
+    }
+
+    /// Keeps the rows, out of those matching the filters, for which
+    /// `rowFilter` returns true. It is called with `batchSize` rows at a
+    /// time, 1000 by default, keyed by input column, and returns one boolean
+    /// per row. It must return synchronously. A throw, or a result other than
+    /// one boolean per row, rejects `saveCsv`.
+    #[napi(
+        ts_args_type = "rowFilter: (rows: Array<Record<string, string>>) => Array<boolean>, batchSize?: number"
+    )]
+    pub fn set_row_filter(
+        &mut self,
+        row_filter: JsFunction,
+        batch_size: Option<u32>
+    ) -> Result<(), napi::Error> {
+        self.inner.set_row_filter(create_callback(row_filter)?, batch_size.unwrap_or(1000) as usize);
+        Ok(())
+    }
+
+    /// Deduplicates the rows written by `saveCsv`, see `deduplicate`.
+    #[napi]
+    pub fn set_deduplication(&mut self, options: DeduplicateOptions) -> Result<(), napi::Error> {
+        self.inner.set_deduplication(crate::create_deduplication(options)?);
+        Ok(())
     }
 
     #[napi]
@@ -336,8 +969,9 @@ impl JsTransform {
     pub fn save_csv(&'static mut self, env: Env, path: String) -> Result<JsObject, napi::Error> {
         let res = env.execute_tokio_future(
             async move {
-                self.inner.save_to(path).unwrap();
-                Ok(())
+                self.inner
+                    .save_to(path)
+                    .map_err(|err| napi::Error::new(napi::Status::GenericFailure, err.to_string()))
             },
             |_env, _| Ok(())
         );
@@ -346,6 +980,26 @@ impl JsTransform {
     }
 }
 
+pub struct AsyncTransformTask {
+    pub transform: Transform,
+    pub output: String,
+}
+
+impl Task for AsyncTransformTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<()> {
+        self.transform
+            .save_to(self.output.clone())
+            .map_err(|err| napi::Error::new(napi::Status::GenericFailure, err.to_string()))
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> napi::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -426,6 +1080,236 @@ mod tests {
         fs::remove_file(&output_file).unwrap();
     }
 
+    fn read_keys(output_file: &str) -> Vec<String> {
+        let mut reader = ReaderBuilder::new().delimiter(b'\t').from_path(output_file).unwrap();
+        let keys = reader
+            .records()
+            .map(|rec| format!("{}:{}", &rec.as_ref().unwrap()[0], &rec.as_ref().unwrap()[1]))
+            .collect();
+        fs::remove_file(output_file).unwrap();
+        keys
+    }
+
+    #[test]
+    fn test_transform_with_filter_tree() {
+        let filter = |field: &str, value: &str, comparison| {
+            FilterTree::from(
+                &Filter::create(
+                    String::from(field),
+                    String::from(value),
+                    Some(FieldType::Number),
+                    Some(comparison)
+                )
+            )
+        };
+        let mut transform = Transform::new(String::from(FILE_FIXTURE));
+        transform.add_filter(
+            FilterTree::Or(vec![filter("key", "1", Comparison::Eq), filter("key", "78", Comparison::Eq)])
+        );
+        transform.add_filter(FilterTree::Not(Box::new(filter("feature_left", "900", Comparison::Eq))));
+
+        let output_file = "./output_with_filter_tree.csv";
+        assert!(transform.save_to(output_file.to_string()).is_ok());
+        assert_eq!(read_keys(output_file), ["1:100", "78:950"]);
+    }
+
+    #[test]
+    fn test_transform_with_comparisons() {
+        let run = |field_type, comparison, value: &str, options: FilterOptions| {
+            let mut transform = Transform::new(String::from(FILE_FIXTURE));
+            transform.add_filter(
+                &Filter::create(
+                    String::from("feature_left"),
+                    String::from(value),
+                    Some(field_type),
+                    Some(comparison)
+                ).with_options(options)
+            );
+            let output_file = format!("./output_with_{:?}_{:?}.csv", field_type, comparison);
+            transform.save_to(output_file.clone()).unwrap();
+            read_keys(&output_file)
+        };
+        let none = FilterOptions::default;
+
+        assert_eq!(run(FieldType::Number, Comparison::Ne, "900", none()).len(), 12);
+        assert_eq!(
+            run(FieldType::Number, Comparison::In, "", FilterOptions {
+                values: Some(vec![String::from("150"), String::from("7000")]),
+                ..FilterOptions::default()
+            }),
+            ["2:150", "2:150", "300:7000"]
+        );
+        assert_eq!(
+            run(FieldType::Number, Comparison::Between, "600", FilterOptions {
+                to: Some(String::from("700")),
+                ..FilterOptions::default()
+            }),
+            ["43:600", "88:700"]
+        );
+        assert_eq!(run(FieldType::String, Comparison::Contains, "95", none()), ["78:950"]);
+        assert_eq!(run(FieldType::String, Comparison::StartsWith, "70", none()), ["88:700", "300:7000"]);
+        assert_eq!(run(FieldType::Number, Comparison::EndsWith, "50", none()), ["2:150", "2:150", "78:950"]);
+        assert_eq!(run(FieldType::String, Comparison::Regex, "^[12]", none()), ["1:100", "2:150", "2:200", "2:150"]);
+        assert_eq!(run(FieldType::String, Comparison::IsEmpty, "", none()).len(), 0);
+        assert_eq!(run(FieldType::String, Comparison::IsNotEmpty, "", none()).len(), 16);
+
+        let mut transform = Transform::new(String::from(FILE_FIXTURE));
+        transform.add_filter(
+            &Filter::create(String::from("key"), String::from(""), None, Some(Comparison::In))
+        );
+        let err = transform.save_to(String::from("./output_with_in_error.csv")).unwrap_err();
+        assert_eq!(err.to_string(), "Filter on key needs values for Comparison.In");
+    }
+
+    #[test]
+    fn test_transform_with_invalid_values() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        fs::write(input.path(), "key\tamount\n1\t10\n2\t\n3\tn/a\n4\t40\n").unwrap();
+        let run = |policy| {
+            let mut transform = Transform::new(input.path().to_string_lossy().to_string());
+            transform.add_filter(
+                &Filter::create(
+                    String::from("amount"),
+                    String::from("20"),
+                    Some(FieldType::Number),
+                    Some(Comparison::Lt)
+                ).with_options(FilterOptions {
+                    on_invalid: Some(policy),
+                    ..FilterOptions::default()
+                })
+            );
+            let output_file = format!("./output_with_invalid_{:?}.csv", policy);
+            transform.save_to(output_file.clone()).map(|_| read_keys(&output_file))
+        };
+
+        assert_eq!(run(InvalidValuePolicy::NonMatch).unwrap(), ["1:10"]);
+        assert_eq!(run(InvalidValuePolicy::Match).unwrap(), ["1:10", "2:", "3:n/a"]);
+        assert_eq!(
+            run(InvalidValuePolicy::Fail).unwrap_err().to_string(),
+            "Invalid Number value for amount on line 3: "
+        );
+        fs::remove_file("./output_with_invalid_Fail.csv").unwrap();
+
+        let filter = Filter::create(String::from("amount"), String::from("ten"), Some(FieldType::Number), None);
+        assert_eq!(
+            FilterTree::from(&filter).validate().unwrap_err().to_string(),
+            "Invalid Number filter value for amount: ten"
+        );
+    }
+
+    #[test]
+    fn test_transform_with_value_column() {
+        let run = |field_type, comparison| {
+            let mut transform = Transform::new(String::from(FILE_FIXTURE));
+            transform.add_filter(
+                &Filter::create(
+                    String::from("key"),
+                    String::new(),
+                    Some(field_type),
+                    Some(comparison)
+                ).with_options(FilterOptions {
+                    value_column: Some(String::from("feature2_left")),
+                    ..FilterOptions::default()
+                })
+            );
+            let output_file = format!("./output_with_value_column_{:?}_{:?}.csv", field_type, comparison);
+            transform.save_to(output_file.clone()).map(|_| read_keys(&output_file))
+        };
+
+        assert_eq!(run(FieldType::Number, Comparison::Eq).unwrap(), ["1:100", "2:200"]);
+        assert_eq!(run(FieldType::Number, Comparison::Gt).unwrap().len(), 13);
+        assert_eq!(run(FieldType::String, Comparison::Gt).unwrap().len(), 12);
+        assert_eq!(run(FieldType::Number, Comparison::Lt).unwrap(), ["2:300"]);
+        assert_eq!(
+            run(FieldType::Number, Comparison::Between).unwrap_err().to_string(),
+            "Filter on key cannot compare columns with Comparison.Between"
+        );
+    }
+
+    #[test]
+    fn test_transform_with_key_set() {
+        let lines = tempfile::NamedTempFile::new().unwrap();
+        fs::write(lines.path(), "78\r\n\n2\n").unwrap();
+        let table = tempfile::NamedTempFile::new().unwrap();
+        fs::write(table.path(), "name,user_id\nb,300\na,1\n").unwrap();
+        let run = |path: &std::path::Path, options: FilterOptions| {
+            let output_file = format!("./output_with_key_set_{:?}.csv", options.hash_keys);
+            let mut transform = Transform::new(String::from(FILE_FIXTURE));
+            transform.add_filter(
+                &Filter::create(
+                    String::from("key"),
+                    path.to_string_lossy().to_string(),
+                    None,
+                    Some(Comparison::InKeySet)
+                ).with_options(options)
+            );
+            transform.save_to(output_file.clone()).map(|_| read_keys(&output_file))
+        };
+
+        assert_eq!(run(lines.path(), FilterOptions::default()).unwrap().len(), 8);
+        let table_options = FilterOptions {
+            key_column: Some(String::from("user_id")),
+            key_delimiter: Some(b','),
+            ..FilterOptions::default()
+        };
+        assert_eq!(run(table.path(), table_options.clone()).unwrap(), ["1:100", "300:7000"]);
+        assert_eq!(
+            run(table.path(), FilterOptions { hash_keys: Some(true), ..table_options }).unwrap(),
+            ["1:100", "300:7000"]
+        );
+        assert_eq!(
+            run(table.path(), FilterOptions { key_column: Some(String::from("id")), ..FilterOptions::default() })
+                .unwrap_err()
+                .to_string(),
+            format!("Not found key field id in {}", table.path().display())
+        );
+    }
+
+    #[test]
+    fn test_transform_with_deduplication() {
+        let mut transform = Transform::new(String::from(FILE_FIXTURE));
+        transform.add_filter(
+            &Filter::create(
+                String::from("key"),
+                String::from("78"),
+                Some(FieldType::Number),
+                Some(Comparison::Le)
+            )
+        );
+        transform.set_deduplication(Deduplication {
+            key: Some(String::from("key")),
+            strategy: DeduplicateStrategy::RemoveSimilar,
+            aggregations: vec![],
+            similarity_columns: None,
+            similarity_ignore_columns: vec![],
+            group_memory_limit: None,
+            audit_output: None,
+            group_count_columns: true,
+            group_callback: None,
+            group_batch_size: 1000,
+        });
+
+        let output_file = "./output_with_deduplication.csv";
+        assert!(transform.save_to(output_file.to_string()).is_ok());
+
+        let output = fs::read_to_string(output_file).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "key\tfeature_left\tfeature2_left\t__group_size\t__group_left\t__group_right");
+        assert_eq!(lines[1..], [
+            "1\t100\t1\t1\t1\t0",
+            "2\t150\t1\t2\t2\t0",
+            "2\t200\t2\t1\t1\t0",
+            "2\t300\t3\t1\t1\t0",
+            "6\t300\t1\t1\t1\t0",
+            "7\t400\t1\t1\t1\t0",
+            "12\t500\t2\t1\t1\t0",
+            "43\t600\t2\t1\t1\t0",
+            "78\t900\t2\t3\t3\t0",
+            "78\t950\t2\t1\t1\t0",
+        ]);
+        fs::remove_file(output_file).unwrap();
+    }
+
     // #[test]
     // fn test_transform_with_columns_transform() {
     //     let columns_transform = |s: String| {
@@ -1,4 +1,9 @@
 use std::fs::{ self, File };
+use std::thread;
+
+use napi::bindgen_prelude::ToNapiValue;
+use napi::threadsafe_function::{ ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction };
+use napi::{ Env, JsFunction, JsObject, Task };
 
 pub fn is_empty_file(path: &String) -> std::io::Result<bool> {
     let metadata = fs::metadata(path)?;
@@ -9,4 +14,35 @@ pub fn is_empty_file(path: &String) -> std::io::Result<bool> {
 
 pub fn create_empty_file(path: &String) -> std::io::Result<File> {
     File::create(path)
-}
\ No newline at end of file
+}
+
+/// Threadsafe function calling `func` with the value as its only argument.
+/// A throw or a return value of the wrong type comes back as an error from
+/// `call_async`, where `ErrorStrategy::Fatal` would abort the process.
+pub fn create_callback<T: ToNapiValue + 'static>(
+    func: JsFunction
+) -> napi::Result<ThreadsafeFunction<T, ErrorStrategy::CalleeHandled>> {
+    // `CalleeHandled` passes a leading error argument, taken as `this` by
+    // `Function.prototype.call` bound to `func`.
+    let func: JsObject = func.coerce_to_object()?;
+    let call: JsObject = func.get_named_property::<JsFunction>("call")?.coerce_to_object()?;
+    let bind: JsFunction = call.get_named_property("bind")?;
+    let bound: JsFunction = bind.call(Some(&call), &[func])?.try_into()?;
+    bound.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<T>| Ok(vec![ctx.value]))
+}
+
+/// Runs `task` on a thread of its own and returns a promise for its result.
+/// Unlike `AsyncTask`, it does not hold a libuv pool thread, which tasks
+/// blocking on Node stream input must not do: `fs` streams read on that pool.
+pub fn spawn_task<T: Task + 'static>(env: &Env, mut task: T) -> napi::Result<JsObject>
+    where T::JsValue: ToNapiValue
+{
+    let (deferred, promise) = env.create_deferred()?;
+    thread::spawn(move || {
+        match task.compute() {
+            Ok(output) => deferred.resolve(move |env| task.resolve(env, output)),
+            Err(err) => deferred.reject(err),
+        }
+    });
+    Ok(promise)
+}
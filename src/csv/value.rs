@@ -0,0 +1,314 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::transform::FieldType;
+
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+pub const DEFAULT_DATE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// A cell or filter value parsed according to its `FieldType`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    Number(i64),
+    Float(f64),
+    Decimal(Decimal),
+    /// Year, month, day, hour, minute and second.
+    DateTime([i64; 6]),
+    Boolean(bool),
+    String(String),
+}
+
+impl TypedValue {
+    /// Parses `text` as `field_type`, `format` being used by dates only.
+    pub fn parse(field_type: FieldType, text: &str, format: Option<&str>) -> Option<TypedValue> {
+        match field_type {
+            FieldType::String => Some(TypedValue::String(text.to_string())),
+            FieldType::Number => text.trim().parse().ok().map(TypedValue::Number),
+            FieldType::Float =>
+                text
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| !number.is_nan())
+                    .map(TypedValue::Float),
+            FieldType::Decimal => Decimal::parse(text.trim()).map(TypedValue::Decimal),
+            FieldType::Date =>
+                parse_date_time(text.trim(), format.unwrap_or(DEFAULT_DATE_FORMAT)).map(
+                    TypedValue::DateTime
+                ),
+            FieldType::DateTime =>
+                parse_date_time(text.trim(), format.unwrap_or(DEFAULT_DATE_TIME_FORMAT)).map(
+                    TypedValue::DateTime
+                ),
+            FieldType::Boolean =>
+                match text.trim().to_ascii_lowercase().as_str() {
+                    "true" | "t" | "yes" | "y" | "1" => Some(TypedValue::Boolean(true)),
+                    "false" | "f" | "no" | "n" | "0" => Some(TypedValue::Boolean(false)),
+                    _ => None,
+                }
+        }
+    }
+
+    /// Values of different types, which a filter never produces, compare equal.
+    pub fn compare(&self, other: &TypedValue) -> Ordering {
+        match (self, other) {
+            (TypedValue::Number(a), TypedValue::Number(b)) => a.cmp(b),
+            (TypedValue::Float(a), TypedValue::Float(b)) => a.total_cmp(b),
+            (TypedValue::Decimal(a), TypedValue::Decimal(b)) => a.cmp(b),
+            (TypedValue::DateTime(a), TypedValue::DateTime(b)) => a.cmp(b),
+            (TypedValue::Boolean(a), TypedValue::Boolean(b)) => a.cmp(b),
+            (TypedValue::String(a), TypedValue::String(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// Decimal number compared exactly, without going through floating point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decimal {
+    negative: bool,
+    /// Integer digits without leading zeros.
+    integer: String,
+    /// Fraction digits without trailing zeros.
+    fraction: String,
+}
+
+impl Decimal {
+    pub fn parse(text: &str) -> Option<Decimal> {
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let is_digits = |digits: &str| digits.bytes().all(|b| b.is_ascii_digit());
+        if (integer.is_empty() && fraction.is_empty()) || !is_digits(integer) || !is_digits(fraction) {
+            return None;
+        }
+
+        let integer = integer.trim_start_matches('0').to_string();
+        let fraction = fraction.trim_end_matches('0').to_string();
+        Some(Decimal {
+            // -0 and 0 are the same value.
+            negative: negative && !(integer.is_empty() && fraction.is_empty()),
+            integer,
+            fraction,
+        })
+    }
+
+    /// Exact sum, `None` once it no longer fits 38 digits.
+    pub fn checked_sum(values: &[Decimal]) -> Option<Decimal> {
+        let scale = values
+            .iter()
+            .map(|value| value.fraction.len())
+            .max()
+            .unwrap_or(0);
+        let mut sum: i128 = 0;
+        for value in values {
+            sum = sum.checked_add(value.to_scaled(scale)?)?;
+        }
+        Some(Decimal::from_scaled(sum, scale))
+    }
+
+    /// Exact quotient, `None` when it has no finite decimal expansion within
+    /// 38 digits, as for a third.
+    pub fn checked_div(&self, divisor: u32) -> Option<Decimal> {
+        let divisor = i128::from(divisor);
+        let mut scale = self.fraction.len();
+        let mut value = self.to_scaled(scale)?;
+        while value % divisor != 0 {
+            value = value.checked_mul(10)?;
+            scale += 1;
+        }
+        Some(Decimal::from_scaled(value / divisor, scale))
+    }
+
+    /// The value times `10^scale`, `scale` not being below the number of
+    /// fraction digits.
+    fn to_scaled(&self, scale: usize) -> Option<i128> {
+        let digits = format!("{}{:0<scale$}", self.integer, self.fraction);
+        let magnitude: i128 = if digits.is_empty() { 0 } else { digits.parse().ok()? };
+        Some(if self.negative { -magnitude } else { magnitude })
+    }
+
+    fn from_scaled(value: i128, scale: usize) -> Decimal {
+        let digits = format!("{:0>width$}", value.unsigned_abs(), width = scale + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - scale);
+        Decimal {
+            negative: value < 0,
+            integer: integer.trim_start_matches('0').to_string(),
+            fraction: fraction.trim_end_matches('0').to_string(),
+        }
+    }
+
+    fn cmp_magnitude(&self, other: &Decimal) -> Ordering {
+        self.integer.len()
+            .cmp(&other.integer.len())
+            .then_with(|| self.integer.cmp(&other.integer))
+            .then_with(|| self.fraction.cmp(&other.fraction))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.cmp_magnitude(other),
+            (true, true) => other.cmp_magnitude(self),
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Shortest form, as `f64` prints: `1.5` for `01.50`, `0` for `-0.0`.
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        if self.integer.is_empty() {
+            write!(f, "0")?;
+        }
+        write!(f, "{}", self.integer)?;
+        if !self.fraction.is_empty() {
+            write!(f, ".{}", self.fraction)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a date with `%Y`, `%m`, `%d`, `%H`, `%M` and `%S`; other
+/// characters of the format must match literally.
+fn parse_date_time(text: &str, format: &str) -> Option<[i64; 6]> {
+    // Month and day default to 1, so that a date compares before its times.
+    let mut parts = [0, 1, 1, 0, 0, 0];
+    let mut text = text.as_bytes();
+    let mut format = format.bytes();
+    while let Some(c) = format.next() {
+        if c != b'%' {
+            text = text.strip_prefix(&[c])?;
+            continue;
+        }
+        let (part, max_digits) = match format.next()? {
+            b'Y' => (0, 4),
+            b'm' => (1, 2),
+            b'd' => (2, 2),
+            b'H' => (3, 2),
+            b'M' => (4, 2),
+            b'S' => (5, 2),
+            b'%' => {
+                text = text.strip_prefix(b"%")?;
+                continue;
+            }
+            _ => {
+                return None;
+            }
+        };
+        let digits = text
+            .iter()
+            .take(max_digits)
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if digits == 0 {
+            return None;
+        }
+        parts[part] = std::str::from_utf8(&text[..digits]).ok()?.parse().ok()?;
+        text = &text[digits..];
+    }
+    if !text.is_empty() {
+        return None;
+    }
+
+    let [year, month, day, hour, minute, second] = parts;
+    let valid =
+        (1..=12).contains(&month) &&
+        day >= 1 &&
+        day <= days_in_month(year, month) &&
+        hour < 24 &&
+        minute < 60 &&
+        second < 60;
+    valid.then_some(parts)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(field_type: FieldType, text: &str) -> Option<TypedValue> {
+        TypedValue::parse(field_type, text, None)
+    }
+
+    #[test]
+    fn test_decimal_ordering() {
+        let decimal = |text| Decimal::parse(text).unwrap();
+        assert_eq!(decimal("9.990"), decimal("9.99"));
+        assert_eq!(decimal("-0.0"), decimal("0"));
+        assert!(decimal("10") > decimal("9.99"));
+        assert!(decimal("-10") < decimal("-9.99"));
+        assert!(decimal(".5") < decimal("0.51"));
+        assert_eq!(Decimal::parse("1.2.3"), None);
+        assert_eq!(Decimal::parse("."), None);
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        let decimals = |texts: &[&str]| -> Vec<Decimal> {
+            texts.iter().map(|text| Decimal::parse(text).unwrap()).collect()
+        };
+        let sum = |texts: &[&str]| {
+            Decimal::checked_sum(&decimals(texts)).map(|sum| sum.to_string())
+        };
+        assert_eq!(sum(&["0.1", "0.2"]).unwrap(), "0.3");
+        assert_eq!(sum(&["9007199254740993", "1"]).unwrap(), "9007199254740994");
+        assert_eq!(sum(&["-1.25", "0.25"]).unwrap(), "-1");
+        assert_eq!(sum(&["-0.5", "0.5"]).unwrap(), "0");
+        assert_eq!(sum(&[]).unwrap(), "0");
+        assert_eq!(sum(&["99999999999999999999999999999999999999"; 2]), None);
+
+        let decimal = |text| Decimal::parse(text).unwrap();
+        assert_eq!(decimal("0.3").checked_div(2).unwrap().to_string(), "0.15");
+        assert_eq!(decimal("-7").checked_div(8).unwrap().to_string(), "-0.875");
+        assert_eq!(decimal("1").checked_div(3), None);
+        assert_eq!(decimal("0050.50").to_string(), "50.5");
+        assert_eq!(decimal(".5").to_string(), "0.5");
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(parse(FieldType::Date, "2024-02-29"), Some(TypedValue::DateTime([2024, 2, 29, 0, 0, 0])));
+        assert_eq!(parse(FieldType::Date, "2023-02-29"), None);
+        assert_eq!(parse(FieldType::Date, "2024-01-01T10:00:00"), None);
+        assert_eq!(
+            parse(FieldType::DateTime, "2024-01-01T10:05:00"),
+            Some(TypedValue::DateTime([2024, 1, 1, 10, 5, 0]))
+        );
+        assert_eq!(
+            TypedValue::parse(FieldType::Date, "31/12/2023", Some("%d/%m/%Y")),
+            Some(TypedValue::DateTime([2023, 12, 31, 0, 0, 0]))
+        );
+    }
+
+    #[test]
+    fn test_other_types() {
+        assert_eq!(parse(FieldType::Float, " 9.99"), Some(TypedValue::Float(9.99)));
+        assert_eq!(parse(FieldType::Float, "NaN"), None);
+        assert_eq!(parse(FieldType::Boolean, "Yes"), Some(TypedValue::Boolean(true)));
+        assert_eq!(parse(FieldType::Boolean, "0"), Some(TypedValue::Boolean(false)));
+        assert_eq!(parse(FieldType::Boolean, "maybe"), None);
+        assert_eq!(parse(FieldType::Number, "4.5"), None);
+    }
+}
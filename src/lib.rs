@@ -1,11 +1,17 @@
 #![deny(clippy::all)]
 
-use crate::csv::merge::AsyncMergeTask;
+use crate::csv::input::MergeInput;
+use crate::csv::merge::{ AsyncMergeTask, GroupCallback, MergeSettings };
+use crate::csv::rows::{ spawn_merge_rows, MergeRowsIterator };
+use crate::csv::transform::{ AsyncTransformTask, Deduplication, Transform };
+use crate::csv::utils::{ create_callback, spawn_task };
 use napi::{
     bindgen_prelude::*,
+    JsObject,
     threadsafe_function::{ ThreadSafeCallContext, ThreadsafeFunction, ErrorStrategy },
 };
 use napi_derive::*;
+use std::collections::HashMap;
 
 extern crate napi_derive;
 
@@ -28,27 +34,288 @@ pub enum DeduplicateStrategy {
     Reduce,
     CrossJoin,
     CrossJoinAndRemoveSimilar,
+    KeepLast,
+    /// Hands the rows of each key to `groupCallback`, which returns the rows
+    /// to write.
+    Custom,
+    /// Keeps the first row of every value of `similarityColumns`, whole rows
+    /// by default, wherever it occurs. Does not need rows sorted by key,
+    /// though `merge` only takes unsorted input with `MergeStrategy.Or`, as
+    /// `And` and `AndNot` still match rows by key.
+    Distinct,
+}
+
+#[napi]
+pub enum KeyNormalization {
+    Trim,
+    Lowercase,
+    StripLeadingZeros,
+    Nfkc,
+    DigitsOnly,
+}
+
+/// Handling of rows whose key is empty or one of `nullTokens`. Such rows may
+/// appear anywhere in an otherwise sorted input.
+#[napi]
+#[derive(PartialEq)]
+pub enum EmptyKeyPolicy {
+    /// Empty keys of both sides match each other, as one more key written
+    /// after all others.
+    MatchEachOther,
+    NeverMatch,
+    Drop,
+    Reject,
+}
+
+/// Order in which left and right rows sharing a key reach the deduplicate
+/// strategy. With `Reduce` the later row wins, with `KeepFirst` the earlier one.
+#[napi]
+#[derive(PartialEq)]
+pub enum TieBreak {
+    /// Rows are passed on in the order the merge loop reads them, which
+    /// interleaves both sides.
+    InputOrder,
+    /// All left rows of a key go first, then all right rows.
+    LeftFirst,
+    /// All right rows of a key go first, then all left rows.
+    RightFirst,
+}
+
+/// `Sum`, `Min`, `Max` and `Mean` are exact while a group's values are plain
+/// decimals like `19.99`, and use floating point otherwise.
+#[napi]
+pub enum AggregationFunction {
+    Sum,
+    Min,
+    Max,
+    Count,
+    CountDistinct,
+    First,
+    Last,
+    Concat,
+    Mean,
+}
+
+/// Per-column aggregation applied by the `Reduce` strategy. Columns without
+/// one keep the last non-empty value of the group.
+#[napi(object)]
+#[derive(Clone)]
+pub struct ColumnAggregation {
+    pub column: String,
+    pub function: AggregationFunction,
+    /// Separator used by `Concat`, empty by default.
+    pub separator: Option<String>,
+}
+
+/// How `Reduce` and `CrossJoin` pick a column value when the left and right
+/// rows of a key disagree.
+#[napi]
+pub enum ConflictPolicy {
+    LastNonEmpty,
+    PreferLeft,
+    PreferRight,
+    FirstNonEmpty,
+    /// Fails the merge on the first conflict.
+    Error,
+    /// Keeps the last non-empty value and writes the conflict to
+    /// `conflictReportOutput`.
+    Report,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct ColumnConflictPolicy {
+    pub column: String,
+    pub policy: ConflictPolicy,
+}
+
+#[napi]
+pub enum CrossJoinLimitAction {
+    /// Fails the merge.
+    Fail,
+    /// Keeps the first rows of the key up to the limit.
+    Truncate,
+    /// Drops all rows of the key.
+    Skip,
+}
+
+/// Bounds on what `CrossJoin` materializes for a single key.
+#[napi(object)]
+#[derive(Clone)]
+pub struct CrossJoinLimits {
+    /// Rows of both sides sharing a key.
+    pub max_group_size: Option<u32>,
+    /// Left × right combinations written for a key.
+    pub max_product: Option<u32>,
+    /// `Fail` by default.
+    pub action: Option<CrossJoinLimitAction>,
+}
+
+#[napi(object)]
+pub struct MergeStats {
+    /// Keys truncated or skipped because of `crossJoinLimits`.
+    pub cross_join_limited_keys: Vec<String>,
+    /// Why each of `crossJoinLimitedKeys` was limited.
+    pub warnings: Vec<String>,
+}
+
+/// Rows sharing a key, as passed to `groupCallback`.
+#[napi(object)]
+pub struct KeyGroup {
+    pub key: String,
+    pub left: Vec<HashMap<String, String>>,
+    pub right: Vec<HashMap<String, String>>,
+}
+
+#[napi]
+pub enum RowFormat {
+    Object,
+    Array,
 }
 
 #[napi(object)]
 pub struct MergeOptions {
-    pub output: String,
+    /// Required by `merge`, ignored by `mergeRows`.
+    pub output: Option<String>,
     pub merge_strategy: MergeStrategy,
     pub deduplicate_strategy: DeduplicateStrategy,
     pub left_key: String,
     pub right_key: String,
     pub is_number_key: Option<bool>,
+    /// Steps applied to both keys before comparing them. Inputs must be
+    /// sorted by the normalized key, the merge fails on a key that sorts
+    /// before the previous one.
+    pub key_normalization: Option<Vec<KeyNormalization>>,
+    pub empty_key_policy: Option<EmptyKeyPolicy>,
+    pub empty_key_reject_output: Option<String>,
+    /// Key cells treated as empty keys. Matched after `keyNormalization`,
+    /// which is applied to the tokens as well.
+    pub null_tokens: Option<Vec<String>>,
+    pub append_line_number: Option<bool>,
+    pub append_source_path: Option<bool>,
+    pub tie_break: Option<TieBreak>,
+    pub aggregations: Option<Vec<ColumnAggregation>>,
+    pub conflict_policies: Option<Vec<ColumnConflictPolicy>>,
+    pub conflict_report_output: Option<String>,
+    pub cross_join_limits: Option<CrossJoinLimits>,
+    /// Bytes of a key group that `RemoveSimilar`, `Reduce` and `CrossJoin`
+    /// keep in memory before spilling it to temporary files, or of the values
+    /// `Distinct` has seen. Also bounds each side of a key held back by
+    /// `tieBreak`. Unlimited by default.
+    pub group_memory_limit: Option<u32>,
+    /// Columns `RemoveSimilar`, `CrossJoinAndRemoveSimilar` and `Distinct`
+    /// compare rows on, all by default. Exclusive with
+    /// `similarityIgnoreColumns`.
+    pub similarity_columns: Option<Vec<String>>,
+    /// Columns left out when comparing rows for similarity.
+    pub similarity_ignore_columns: Option<Vec<String>>,
+    /// Appends `__group_size`, `__group_left` and `__group_right`, the input
+    /// rows each output row stands for. `CrossJoin` repeats the counts of the
+    /// key on every combination. Not supported by `Custom`.
+    pub group_count_columns: Option<bool>,
+    /// File listing the rows `KeepFirst`, `KeepLast`, `RemoveSimilar` and
+    /// `Reduce` dropped, with their key, side and the reason.
+    pub audit_output: Option<String>,
+    /// Rows per batch yielded by `mergeRows`, 1000 by default.
+    pub batch_size: Option<u32>,
+    pub row_format: Option<RowFormat>,
 
     #[napi(ts_type = "(columnName: string) => string | undefined")]
     pub output_header_callback: Option<JsFunction>,
+    /// Resolves key groups for `DeduplicateStrategy.Custom`. Called with a
+    /// batch of groups, it returns the rows to write for all of them, keyed
+    /// by output column. It must return synchronously. A throw, or rows that
+    /// are not objects of strings, reject the merge.
+    #[napi(ts_type = "(groups: Array<KeyGroup>) => Array<Record<string, string>>")]
+    pub group_callback: Option<JsFunction>,
+    /// Rows collected before `groupCallback` is called, 1000 by default.
+    /// Groups are never split between calls.
+    pub group_batch_size: Option<u32>,
 }
 
-#[napi(ts_return_type = "Promise<void>")]
-pub fn merge(
-    left_path: String,
-    right_path: String,
+/// Rows of a single file sharing a key, sorted by that key.
+#[napi(object)]
+pub struct DeduplicateOptions {
+    /// Required by `deduplicate`, ignored by `Transform.setDeduplication`.
+    pub output: Option<String>,
+    /// Column the file is sorted by, required by all strategies but
+    /// `Distinct`.
+    pub key: Option<String>,
+    pub strategy: DeduplicateStrategy,
+    pub aggregations: Option<Vec<ColumnAggregation>>,
+    pub similarity_columns: Option<Vec<String>>,
+    pub similarity_ignore_columns: Option<Vec<String>>,
+    pub group_memory_limit: Option<u32>,
+    pub audit_output: Option<String>,
+    pub group_count_columns: Option<bool>,
+    #[napi(ts_type = "(groups: Array<KeyGroup>) => Array<Record<string, string>>")]
+    pub group_callback: Option<JsFunction>,
+    pub group_batch_size: Option<u32>,
+}
+
+fn create_group_callback(
+    group_callback: Option<JsFunction>,
+    strategy: DeduplicateStrategy
+) -> Result<Option<GroupCallback>> {
+    match group_callback {
+        Some(cb) => Ok(Some(create_callback(cb)?)),
+        None if matches!(strategy, DeduplicateStrategy::Custom) =>
+            Err(
+                Error::new(
+                    Status::InvalidArg,
+                    "groupCallback is required for DeduplicateStrategy.Custom".to_string()
+                )
+            ),
+        None => Ok(None),
+    }
+}
+
+fn check_group_count_columns(group_count_columns: bool, strategy: DeduplicateStrategy) -> Result<()> {
+    let strategy = match strategy {
+        DeduplicateStrategy::Custom => "Custom",
+        DeduplicateStrategy::Distinct => "Distinct",
+        _ => {
+            return Ok(());
+        }
+    };
+    if group_count_columns {
+        return Err(
+            Error::new(
+                Status::InvalidArg,
+                format!("groupCountColumns is not supported by DeduplicateStrategy.{}", strategy)
+            )
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn create_deduplication(options: DeduplicateOptions) -> Result<Deduplication> {
+    let group_count_columns = options.group_count_columns.unwrap_or(false);
+    check_group_count_columns(group_count_columns, options.strategy)?;
+    if options.key.is_none() && !matches!(options.strategy, DeduplicateStrategy::Distinct) {
+        return Err(Error::new(Status::InvalidArg, "key is required".to_string()));
+    }
+
+    Ok(Deduplication {
+        key: options.key,
+        strategy: options.strategy,
+        aggregations: options.aggregations.unwrap_or_default(),
+        similarity_columns: options.similarity_columns,
+        similarity_ignore_columns: options.similarity_ignore_columns.unwrap_or_default(),
+        group_memory_limit: options.group_memory_limit.map(|limit| limit as usize),
+        audit_output: options.audit_output,
+        group_count_columns,
+        group_callback: create_group_callback(options.group_callback, options.strategy)?,
+        group_batch_size: options.group_batch_size.unwrap_or(1000) as usize,
+    })
+}
+
+fn create_merge_settings(
+    env: &Env,
+    left_input: Either3<String, Buffer, JsObject>,
+    right_input: Either3<String, Buffer, JsObject>,
     options: MergeOptions
-) -> AsyncTask<AsyncMergeTask> {
+) -> Result<MergeSettings> {
     let output_header_callback: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>> = match
         options.output_header_callback
     {
@@ -60,16 +327,96 @@ pub fn merge(
                 .ok(),
         None => None,
     };
+    let group_count_columns = options.group_count_columns.unwrap_or(false);
+    check_group_count_columns(group_count_columns, options.deduplicate_strategy)?;
+    let group_callback = create_group_callback(
+        options.group_callback,
+        options.deduplicate_strategy
+    )?;
+    // Subscribes to stream inputs, so nothing may fail after it.
+    let (left_input, right_input) = MergeInput::pair_from_js(env, left_input, right_input)?;
 
-    AsyncTask::new(AsyncMergeTask {
-        left_path,
-        right_path,
-        output: options.output,
+    Ok(MergeSettings {
+        left_input: Some(left_input),
+        right_input: Some(right_input),
         merge_strategy: options.merge_strategy,
         deduplicate_strategy: options.deduplicate_strategy,
         left_key: options.left_key,
         right_key: options.right_key,
         is_number_key: options.is_number_key,
+        key_normalization: options.key_normalization.unwrap_or_default(),
+        empty_key_policy: options.empty_key_policy.unwrap_or(EmptyKeyPolicy::MatchEachOther),
+        empty_key_reject_output: options.empty_key_reject_output,
+        null_tokens: options.null_tokens.unwrap_or_default(),
+        append_line_number: options.append_line_number.unwrap_or(false),
+        append_source_path: options.append_source_path.unwrap_or(false),
+        tie_break: options.tie_break.unwrap_or(TieBreak::InputOrder),
+        aggregations: options.aggregations.unwrap_or_default(),
+        conflict_policies: options.conflict_policies.unwrap_or_default(),
+        conflict_report_output: options.conflict_report_output,
+        cross_join_limits: options.cross_join_limits,
+        group_memory_limit: options.group_memory_limit.map(|limit| limit as usize),
+        similarity_columns: options.similarity_columns,
+        similarity_ignore_columns: options.similarity_ignore_columns.unwrap_or_default(),
+        audit_output: options.audit_output,
         output_header_callback,
+        group_callback,
+        group_batch_size: options.group_batch_size.unwrap_or(1000) as usize,
+        group_count_columns,
     })
-}
\ No newline at end of file
+}
+
+#[napi(
+    ts_args_type = "leftInput: string | Buffer | NodeJS.ReadableStream, rightInput: string | Buffer | NodeJS.ReadableStream, options: MergeOptions",
+    ts_return_type = "Promise<MergeStats>"
+)]
+pub fn merge(
+    env: Env,
+    left_input: Either3<String, Buffer, JsObject>,
+    right_input: Either3<String, Buffer, JsObject>,
+    mut options: MergeOptions
+) -> Result<Either<AsyncTask<AsyncMergeTask>, JsObject>> {
+    let output = options.output
+        .take()
+        .ok_or_else(|| Error::new(Status::InvalidArg, "output is required".to_string()))?;
+
+    let task = AsyncMergeTask {
+        settings: create_merge_settings(&env, left_input, right_input, options)?,
+        output,
+    };
+    if task.settings.has_stream_input() {
+        Ok(Either::B(spawn_task(&env, task)?))
+    } else {
+        Ok(Either::A(AsyncTask::new(task)))
+    }
+}
+
+#[napi(
+    ts_args_type = "leftInput: string | Buffer | NodeJS.ReadableStream, rightInput: string | Buffer | NodeJS.ReadableStream, options: MergeOptions",
+    ts_return_type = "AsyncIterableIterator<Array<Record<string, string>> | Array<Array<string>>>"
+)]
+pub fn merge_rows(
+    env: Env,
+    left_input: Either3<String, Buffer, JsObject>,
+    right_input: Either3<String, Buffer, JsObject>,
+    options: MergeOptions
+) -> Result<JsObject> {
+    let batch_size = options.batch_size.unwrap_or(1000) as usize;
+    let row_format = options.row_format.unwrap_or(RowFormat::Object);
+    let settings = create_merge_settings(&env, left_input, right_input, options)?;
+    let stream_input = settings.has_stream_input();
+
+    MergeRowsIterator::create(env, spawn_merge_rows(settings, batch_size), row_format, stream_input)
+}
+
+/// Deduplicates a single tab-separated file sorted by `options.key`.
+#[napi(ts_return_type = "Promise<void>")]
+pub fn deduplicate(path: String, mut options: DeduplicateOptions) -> Result<AsyncTask<AsyncTransformTask>> {
+    let output = options.output
+        .take()
+        .ok_or_else(|| Error::new(Status::InvalidArg, "output is required".to_string()))?;
+
+    let mut transform = Transform::new(path);
+    transform.set_deduplication(create_deduplication(options)?);
+    Ok(AsyncTask::new(AsyncTransformTask { transform, output }))
+}